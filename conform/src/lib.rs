@@ -2,6 +2,7 @@
 
 #[macro_use]
 extern crate error_chain;
+extern crate half;
 extern crate ndarray;
 #[macro_use]
 extern crate proptest;
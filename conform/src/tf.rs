@@ -9,6 +9,7 @@ use tensorflow::Tensor;
 
 use tfdeploy::Matrix;
 
+use half::f16;
 use ndarray::ArrayD;
 
 use ::Result;
@@ -35,10 +36,15 @@ pub fn for_slice(buf: &[u8]) -> Result<Tensorflow> {
 enum TensorHolder {
     F64(Tensor<f64>),
     F32(Tensor<f32>),
+    F16(Tensor<f16>),
+    I64(Tensor<i64>),
     I32(Tensor<i32>),
+    I16(Tensor<i16>),
+    U16(Tensor<u16>),
     U8(Tensor<u8>),
     I8(Tensor<i8>),
-    String(Tensor<i8>),
+    Bool(Tensor<bool>),
+    String(Tensor<String>),
 }
 
 impl TensorHolder {
@@ -48,18 +54,32 @@ impl TensorHolder {
         tensor.copy_from_slice(m.as_slice().unwrap());
         tensor
     }
+
+    fn to_string_tensor(m: ArrayD<Vec<u8>>) -> Result<Tensor<String>> {
+        let dims: Vec<u64> = m.shape().iter().map(|d| *d as _).collect();
+        let mut tensor = Tensor::<String>::new(&*dims);
+        for (t, s) in tensor.iter_mut().zip(m.iter()) {
+            *t = String::from_utf8(s.clone()).map_err(|e| e.utf8_error())?;
+        }
+        Ok(tensor)
+    }
 }
 
-impl From<Matrix> for TensorHolder {
-    fn from(m: Matrix) -> TensorHolder {
-        match m {
+impl TensorHolder {
+    fn from_matrix(m: Matrix) -> Result<TensorHolder> {
+        Ok(match m {
             Matrix::F64(a) => TensorHolder::F64(Self::to_tensor(a)),
             Matrix::F32(a) => TensorHolder::F32(Self::to_tensor(a)),
+            Matrix::F16(a) => TensorHolder::F16(Self::to_tensor(a)),
+            Matrix::I64(a) => TensorHolder::I64(Self::to_tensor(a)),
             Matrix::I32(a) => TensorHolder::I32(Self::to_tensor(a)),
+            Matrix::I16(a) => TensorHolder::I16(Self::to_tensor(a)),
+            Matrix::U16(a) => TensorHolder::U16(Self::to_tensor(a)),
             Matrix::U8(a) => TensorHolder::U8(Self::to_tensor(a)),
             Matrix::I8(a) => TensorHolder::I8(Self::to_tensor(a)),
-            Matrix::String(a) => TensorHolder::String(Self::to_tensor(a)),
-        }
+            Matrix::Bool(a) => TensorHolder::Bool(Self::to_tensor(a)),
+            Matrix::String(a) => TensorHolder::String(Self::to_string_tensor(a)?),
+        })
     }
 }
 
@@ -73,31 +93,41 @@ impl Tensorflow {
         use tensorflow::DataType;
         let tensors: Vec<(&str, TensorHolder)> = inputs
             .into_iter()
-            .map(|(name, mat)| (name, mat.into()))
-            .collect();
+            .map(|(name, mat)| Ok((name, TensorHolder::from_matrix(mat)?)))
+            .collect::<Result<_>>()?;
         let mut step = StepWithGraph::new();
         for t in &tensors {
             let op = self.graph.operation_by_name_required(t.0)?;
             match t.1 {
                 TensorHolder::F64(ref it) => step.add_input(&op, 0, &it),
                 TensorHolder::F32(ref it) => step.add_input(&op, 0, &it),
+                TensorHolder::F16(ref it) => step.add_input(&op, 0, &it),
+                TensorHolder::I64(ref it) => step.add_input(&op, 0, &it),
                 TensorHolder::I32(ref it) => step.add_input(&op, 0, &it),
+                TensorHolder::I16(ref it) => step.add_input(&op, 0, &it),
+                TensorHolder::U16(ref it) => step.add_input(&op, 0, &it),
                 TensorHolder::U8(ref it) => step.add_input(&op, 0, &it),
                 TensorHolder::I8(ref it) => step.add_input(&op, 0, &it),
+                TensorHolder::Bool(ref it) => step.add_input(&op, 0, &it),
                 TensorHolder::String(ref it) => step.add_input(&op, 0, &it),
             }
         }
         let output = step.request_output(&self.graph.operation_by_name_required(output_name)?, 0);
         self.session.run(&mut step)?;
         let matrix = match step.output_data_type(0).unwrap() {
+            DataType::Double => Matrix::F64(tensor_to_matrix(&step.take_output(output)?)?),
             DataType::Float => Matrix::F32(tensor_to_matrix(&step.take_output(output)?)?),
+            DataType::Half => Matrix::F16(tensor_to_matrix(&step.take_output(output)?)?),
+            DataType::Int64 => Matrix::I64(tensor_to_matrix(&step.take_output(output)?)?),
+            DataType::Int16 => Matrix::I16(tensor_to_matrix(&step.take_output(output)?)?),
+            DataType::UInt16 => Matrix::U16(tensor_to_matrix(&step.take_output(output)?)?),
             DataType::UInt8 => Matrix::U8(tensor_to_matrix(&step.take_output(output)?)?),
             DataType::Int8 => Matrix::I8(tensor_to_matrix(&step.take_output(output)?)?),
-            DataType::String => Matrix::String(tensor_to_matrix(&step.take_output(output)?)?),
-            /*
+            DataType::Bool => Matrix::Bool(tensor_to_matrix(&step.take_output(output)?)?),
             DataType::String => {
-                let strings:Tensor<i8> = step.take_output(output)?;
-            }*/
+                let strings: Tensor<String> = step.take_output(output)?;
+                Matrix::String(tensor_to_matrix(&strings)?.map(|s| s.as_bytes().to_vec()))
+            }
             DataType::Int32 => Matrix::I32(tensor_to_matrix(&step.take_output(output)?)?),
             t => Err(format!("Missing tensor to matrix for type {:?}", t))?,
         };
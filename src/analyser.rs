@@ -0,0 +1,361 @@
+//! Static analysis of the tensors flowing through a model.
+//!
+//! Starting from the placeholder attributes and the constants, facts about
+//! each node output (datatype, shape with possibly unknown dimensions, and
+//! value when it can be computed ahead of time) are propagated through the
+//! graph, each op infering its output facts from its input facts.
+
+use std::fmt;
+
+use tfpb::types::DataType;
+use errors::ErrorKind;
+use {Matrix, Model, Result};
+
+/// What is known about a tensor before running the model.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TensorFact {
+    pub datatype: Option<DataType>,
+    /// Dimensions, `None` standing for an unknown dimension. The whole shape
+    /// is `None` when the rank is unknown.
+    pub shape: Option<Vec<Option<usize>>>,
+    pub value: Option<Matrix>,
+}
+
+impl TensorFact {
+    /// A fact with nothing known.
+    pub fn new() -> TensorFact {
+        TensorFact::default()
+    }
+
+    /// A fact with known datatype and shape, but unknown value.
+    pub fn shaped(datatype: Option<DataType>, shape: Vec<Option<usize>>) -> TensorFact {
+        TensorFact {
+            datatype,
+            shape: Some(shape),
+            value: None,
+        }
+    }
+
+    pub fn from_value(value: Matrix) -> TensorFact {
+        TensorFact {
+            datatype: Some(value.datatype()),
+            shape: Some(value.shape().iter().map(|&d| Some(d)).collect()),
+            value: Some(value),
+        }
+    }
+
+    pub fn rank(&self) -> Option<usize> {
+        self.shape.as_ref().map(|s| s.len())
+    }
+
+    /// The shape, if all dimensions are known.
+    pub fn concrete_shape(&self) -> Option<Vec<usize>> {
+        self.shape.as_ref().and_then(|s| s.iter().cloned().collect())
+    }
+
+    /// Is `value` compatible with this fact ?
+    pub fn matches(&self, value: &Matrix) -> bool {
+        if self.datatype.map(|dt| dt != value.datatype()).unwrap_or(false) {
+            return false;
+        }
+        if let Some(ref shape) = self.shape {
+            if shape.len() != value.shape().len()
+                || shape
+                    .iter()
+                    .zip(value.shape())
+                    .any(|(d, &v)| d.map(|d| d != v).unwrap_or(false))
+            {
+                return false;
+            }
+        }
+        self.value.as_ref().map(|v| v == value).unwrap_or(true)
+    }
+}
+
+impl fmt::Display for TensorFact {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.datatype {
+            Some(dt) => write!(f, "{:?}", dt)?,
+            None => write!(f, "?")?,
+        }
+        match self.shape {
+            Some(ref shape) => {
+                let dims: Vec<String> = shape
+                    .iter()
+                    .map(|d| d.map(|d| d.to_string()).unwrap_or("?".to_string()))
+                    .collect();
+                write!(f, " [{}]", dims.join(","))?
+            }
+            None => write!(f, " [..]")?,
+        }
+        if self.value.is_some() {
+            write!(f, " const")?
+        }
+        Ok(())
+    }
+}
+
+/// Numpy-style broadcasting of two shapes.
+pub fn broadcast(a: &[Option<usize>], b: &[Option<usize>]) -> Result<Vec<Option<usize>>> {
+    let rank = a.len().max(b.len());
+    let dim = |s: &[Option<usize>], i: usize| {
+        if i + s.len() < rank {
+            Some(1)
+        } else {
+            s[i + s.len() - rank]
+        }
+    };
+    (0..rank)
+        .map(|i| match (dim(a, i), dim(b, i)) {
+            (Some(1), d) | (d, Some(1)) => Ok(d),
+            (Some(x), Some(y)) if x != y => {
+                Err(format!("Can not broadcast shapes {:?} and {:?}", a, b).into())
+            }
+            (Some(x), _) | (_, Some(x)) => Ok(Some(x)),
+            (None, None) => Ok(None),
+        })
+        .collect()
+}
+
+/// Facts about the outputs of all the nodes of a model.
+#[derive(Clone, Debug)]
+pub struct Analysis {
+    /// Facts for each output port of each node.
+    pub facts: Vec<Vec<TensorFact>>,
+    /// Nodes whose outputs could not be infered, with the reason.
+    pub errors: Vec<(usize, String)>,
+}
+
+impl Analysis {
+    /// Fact about output `port` of `node`.
+    pub fn fact(&self, node: usize, port: usize) -> TensorFact {
+        self.facts[node].get(port).cloned().unwrap_or_default()
+    }
+
+    /// Make sure a value can be fed to a node.
+    pub fn check_feed(&self, model: &Model, node: usize, value: &Matrix) -> Result<()> {
+        let fact = self.fact(node, 0);
+        let (name, op) = (&model.nodes[node].name, &model.nodes[node].op_name);
+        match fact.datatype {
+            Some(dt) if dt != value.datatype() => Err(ErrorKind::DatatypeMismatch(
+                name.clone(),
+                op.clone(),
+                dt,
+                value.datatype(),
+            ))?,
+            _ => (),
+        }
+        match fact.shape {
+            Some(ref shape) if !TensorFact::shaped(None, shape.clone()).matches(value) => {
+                Err(ErrorKind::ShapeMismatch(
+                    name.clone(),
+                    op.clone(),
+                    shape.clone(),
+                    value.shape().to_vec(),
+                ))?
+            }
+            _ => (),
+        }
+        if !fact.matches(value) {
+            Err(format!(
+                "Value fed to {} ({:?} {:?}) does not match {}",
+                model.nodes[node].name,
+                value.datatype(),
+                value.shape(),
+                fact
+            ))?
+        }
+        Ok(())
+    }
+
+    /// One line per node output, with the facts known about it.
+    pub fn dump(&self, model: &Model) -> String {
+        let mut s = String::new();
+        for node in &model.nodes {
+            for (port, fact) in self.facts[node.id].iter().enumerate() {
+                s.push_str(&format!("{}:{} ({}) {}\n", node.name, port, node.op_name, fact));
+            }
+            if let Some(&(_, ref e)) = self.errors.iter().find(|e| e.0 == node.id) {
+                s.push_str(&format!("{} ({}) {}\n", node.name, node.op_name, e));
+            }
+        }
+        s
+    }
+}
+
+/// Infer facts for all the nodes of a model.
+///
+/// `feeds` override the facts for the given nodes, typically placeholders.
+/// Nodes whose inputs are all known constants are evaluated.
+pub fn analyse(model: &Model, feeds: &[(usize, TensorFact)]) -> Analysis {
+    let mut analysis = Analysis {
+        facts: vec![vec![]; model.nodes.len()],
+        errors: vec![],
+    };
+    let mut done = ::bit_set::BitSet::with_capacity(model.nodes.len());
+    for &(node, ref fact) in feeds {
+        analysis.facts[node] = vec![fact.clone()];
+        done.insert(node);
+    }
+    loop {
+        let mut done_something = false;
+        for node in &model.nodes {
+            if !done.contains(node.id)
+                && node.inputs.iter().all(|i| i.1.is_none() || done.contains(i.0))
+            {
+                infer_node(model, node.id, &mut analysis);
+                done.insert(node.id);
+                done_something = true;
+            }
+        }
+        if !done_something {
+            // loops: go on with the first node left, missing inputs being
+            // unknown
+            match (0..model.nodes.len()).find(|&n| !done.contains(n)) {
+                Some(n) => {
+                    infer_node(model, n, &mut analysis);
+                    done.insert(n);
+                }
+                None => break,
+            }
+        }
+    }
+    analysis
+}
+
+fn infer_node(model: &Model, node: usize, analysis: &mut Analysis) {
+    let node = &model.nodes[node];
+    let inputs: Vec<TensorFact> = node.inputs
+        .iter()
+        .filter_map(|&(prec, port)| port.map(|port| analysis.fact(prec, port)))
+        .collect();
+    let constant = node.op_name != "Placeholder"
+        && !::ops::control_flow::CONTROL_FLOW_OPS.contains(&&*node.op_name)
+        && inputs.iter().all(|i| i.value.is_some());
+    if constant {
+        let values = inputs
+            .iter()
+            .map(|i| i.value.clone().unwrap().into())
+            .collect();
+        if let Ok(outputs) = node.eval(values) {
+            analysis.facts[node.id] = outputs
+                .into_iter()
+                .map(|o| TensorFact::from_value(o.into_matrix()))
+                .collect();
+            return;
+        }
+    }
+    match node.op.infer(inputs.iter().collect()) {
+        Ok(facts) => analysis.facts[node.id] = facts,
+        Err(e) => analysis.errors.push((node.id, e.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tfpb;
+    use tfpb::types::DataType::*;
+
+    #[test]
+    fn broadcasting() {
+        assert_eq!(
+            broadcast(&[Some(3), Some(1)], &[Some(4)]).unwrap(),
+            vec![Some(3), Some(4)]
+        );
+        assert_eq!(
+            broadcast(&[None, Some(2)], &[Some(1), Some(2)]).unwrap(),
+            vec![None, Some(2)]
+        );
+        assert!(broadcast(&[Some(3)], &[Some(4)]).is_err());
+    }
+
+    #[test]
+    fn plus3() {
+        let model = ::for_path("tests/plus3.pb").unwrap();
+        let input = TensorFact::shaped(Some(DT_FLOAT), vec![None, Some(2)]);
+        let analysis = model.analyse(&[(0, input.clone())]);
+        assert!(analysis.errors.is_empty());
+        assert_eq!(analysis.fact(1, 0).datatype, Some(DT_FLOAT));
+        assert!(analysis.fact(1, 0).value.is_some());
+        assert_eq!(analysis.fact(2, 0), input);
+        assert!(analysis.dump(&model).contains("output:0 (Add) DT_FLOAT [?,2]"));
+        let ok = Matrix::f32s(&[5, 2], &[0.0; 10]).unwrap();
+        assert!(analysis.check_feed(&model, 0, &ok).is_ok());
+        let ko = Matrix::f32s(&[2, 5], &[0.0; 10]).unwrap();
+        match *analysis.check_feed(&model, 0, &ko).unwrap_err().kind() {
+            ErrorKind::ShapeMismatch(_, _, ref expected, ref found) => {
+                assert_eq!(expected, &vec![None, Some(2)]);
+                assert_eq!(found, &vec![2, 5]);
+            }
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+        let ko = Matrix::i32s(&[5, 2], &[0; 10]).unwrap();
+        match *analysis.check_feed(&model, 0, &ko).unwrap_err().kind() {
+            ErrorKind::DatatypeMismatch(_, _, DT_FLOAT, DT_INT32) => (),
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn shapes() {
+        let mut shape = tfpb::tensor_shape::TensorShapeProto::new();
+        for &d in &[-1, 28, 28, 3] {
+            let mut dim = tfpb::tensor_shape::TensorShapeProto_Dim::new();
+            dim.set_size(d);
+            shape.mut_dim().push(dim);
+        }
+        let mut shape_attr = tfpb::attr_value::AttrValue::new();
+        shape_attr.set_shape(shape);
+        let graph = tfpb::graph()
+            .node(
+                tfpb::node()
+                    .name("image")
+                    .op("Placeholder")
+                    .attr("dtype", DT_FLOAT)
+                    .attr("shape", shape_attr),
+            )
+            .node(
+                tfpb::node()
+                    .name("filter")
+                    .op("Const")
+                    .attr("value", tfpb::tensor_f32(vec![3, 3, 3, 8], vec![0.0; 216])),
+            )
+            .node(
+                tfpb::node()
+                    .name("conv")
+                    .op("Conv2D")
+                    .attr("T", DT_FLOAT)
+                    .attr("strides", vec![1, 2, 2, 1])
+                    .attr("padding", "SAME")
+                    .input("image")
+                    .input("filter"),
+            )
+            .node(
+                tfpb::node()
+                    .name("pool")
+                    .op("MaxPool")
+                    .attr("ksize", vec![1, 2, 2, 1])
+                    .attr("strides", vec![1, 2, 2, 1])
+                    .attr("padding", "VALID")
+                    .input("conv"),
+            )
+            .node(tfpb::node().name("shape").op("Shape").input("pool"))
+            .node(tfpb::node().name("relu").op("Relu").input("pool"))
+            .node(tfpb::node().name("unknown").op("Unknown").input("relu"));
+        let model = Model::new(graph).unwrap();
+        let analysis = model.analyse(&[]);
+        let fact = |name| analysis.fact(model.node_id_by_name(name).unwrap(), 0);
+        assert_eq!(
+            fact("conv").shape,
+            Some(vec![None, Some(14), Some(14), Some(8)])
+        );
+        assert_eq!(
+            fact("relu"),
+            TensorFact::shaped(Some(DT_FLOAT), vec![None, Some(7), Some(7), Some(8)])
+        );
+        assert_eq!(fact("shape").shape, Some(vec![Some(4)]));
+        assert_eq!(analysis.errors.len(), 1);
+        assert_eq!(analysis.errors[0].0, model.node_id_by_name("unknown").unwrap());
+    }
+}
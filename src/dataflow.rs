@@ -0,0 +1,422 @@
+//! Dataflow execution of graphs with control flow.
+//!
+//! Tensorflow v1 control flow graphs can not be run in a static order: a
+//! Switch only feeds one of its branches, and the body of a loop is evaluated
+//! once per iteration. Here, values travel as tokens tagged with the loop
+//! frame and iteration they belong to. Untaken branches receive dead tokens,
+//! which propagate until they reach a Merge or leave a loop.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::Instant;
+
+use {Matrix, Model, ModelState, Plan, Result};
+use ops::Input;
+use ops::control_flow::predicate;
+
+/// Loop frame instance and iteration.
+type Tag = (usize, usize);
+
+const ROOT: Tag = (0, 0);
+
+/// Values of the output ports of a node, `None` standing for a dead node or
+/// port.
+type Outputs = Option<Vec<Option<Input>>>;
+
+#[derive(Clone, Debug)]
+enum Token {
+    Value(Input),
+    Control,
+    Dead,
+}
+
+impl Token {
+    fn is_live(slot: &Option<Token>) -> bool {
+        match slot {
+            &Some(Token::Value(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+struct Frame {
+    /// Tag of the nodes entering the frame.
+    parent: Tag,
+    /// Number of iterations started so far.
+    iterations: usize,
+    /// Constant Enter nodes and their values, fed to each new iteration.
+    constants: Vec<(usize, Outputs)>,
+}
+
+struct Executor<'m> {
+    model: &'m Model,
+    /// Nodes to evaluate.
+    runnable: ::bit_set::BitSet,
+    /// Runnable consumers of each node, with the input slot they use.
+    consumers: Vec<Vec<(usize, usize)>>,
+    frames: Vec<Frame>,
+    children: HashMap<(Tag, String), usize>,
+    pending: HashMap<(usize, Tag), Vec<Option<Token>>>,
+    /// Merge nodes that already fired, and must ignore their other inputs.
+    merged: HashSet<(usize, Tag)>,
+    ready: VecDeque<(usize, Tag, Vec<Option<Token>>)>,
+}
+
+impl Plan {
+    /// Plan for a model with control flow.
+    ///
+    /// No static order is computed: the plan only records the nodes the
+    /// targets depend on, through data or control edges, stopping at the fed
+    /// nodes.
+    pub(crate) fn for_dataflow(model: &Model, inputs: &[usize], targets: &[usize]) -> Plan {
+        let mut needed = ::bit_set::BitSet::with_capacity(model.nodes.len());
+        let mut todo: Vec<usize> = targets.to_vec();
+        while let Some(n) = todo.pop() {
+            if inputs.contains(&n) || !needed.insert(n) {
+                continue;
+            }
+            todo.extend(model.nodes[n].inputs.iter().map(|i| i.0));
+        }
+        Plan {
+            inputs: inputs.to_vec(),
+            targets: targets.to_vec(),
+            steps: vec![],
+            dataflow: Some(needed),
+        }
+    }
+
+    /// Run a plan for a model with control flow.
+    ///
+    /// Only values of the root frame are stored in the state, and only for
+    /// targets and retained nodes, unless the state is incremental.
+    pub(crate) fn run_dataflow(&self, state: &mut ModelState) -> Result<()> {
+        let model = state.model.clone();
+        let model = &*model;
+        let needed = self.dataflow.as_ref().ok_or("Plan is not a dataflow plan")?;
+        let mut runnable = needed.clone();
+        for n in needed.iter() {
+            if state.outputs[n].is_some() {
+                runnable.remove(n);
+            }
+        }
+        let mut exec = Executor {
+            model,
+            consumers: vec![vec![]; model.nodes.len()],
+            runnable,
+            frames: vec![Frame {
+                parent: ROOT,
+                iterations: 1,
+                constants: vec![],
+            }],
+            children: HashMap::new(),
+            pending: HashMap::new(),
+            merged: HashSet::new(),
+            ready: VecDeque::new(),
+        };
+        let mut sources = ::bit_set::BitSet::with_capacity(model.nodes.len());
+        for n in exec.runnable.iter() {
+            let node = &model.nodes[n];
+            for (slot, &(prec, _)) in node.inputs.iter().enumerate() {
+                exec.consumers[prec].push((n, slot));
+                if !exec.runnable.contains(prec) {
+                    if state.outputs[prec].is_none() {
+                        Err(format!(
+                            "Computing {}, precursor {} not done",
+                            node.name, model.nodes[prec].name
+                        ))?
+                    }
+                    sources.insert(prec);
+                }
+            }
+            if node.inputs.is_empty() {
+                exec.ready.push_back((n, ROOT, vec![]));
+            }
+        }
+        for s in sources.iter() {
+            let outputs = state.outputs[s]
+                .as_ref()
+                .map(|o| o.iter().cloned().map(Some).collect());
+            exec.send(s, ROOT, &outputs)?;
+        }
+        while let Some((node, tag, slots)) = exec.ready.pop_front() {
+            exec.fire(state, self, node, tag, slots)?;
+        }
+        for &t in &self.targets {
+            if state.outputs[t].is_none() {
+                Err(format!(
+                    "Node {} was not computed, it may be on an untaken branch",
+                    model.nodes[t].name
+                ))?
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'m> Executor<'m> {
+    /// Send the outputs of a node to its consumers in the frame iteration
+    /// `tag`.
+    fn send(&mut self, node: usize, tag: Tag, outputs: &Outputs) -> Result<()> {
+        for ix in 0..self.consumers[node].len() {
+            let (consumer, slot) = self.consumers[node][ix];
+            let token = match (outputs, self.model.nodes[consumer].inputs[slot].1) {
+                (&None, _) => Token::Dead,
+                (&Some(_), None) => Token::Control,
+                (&Some(ref ports), Some(port)) => match ports.get(port) {
+                    Some(&Some(ref value)) => Token::Value(value.clone()),
+                    Some(&None) => Token::Dead,
+                    None => Err(format!(
+                        "Computing {}, precursor {} has no output {}",
+                        self.model.nodes[consumer].name, self.model.nodes[node].name, port
+                    ))?,
+                },
+            };
+            self.deliver(consumer, slot, tag, token);
+        }
+        Ok(())
+    }
+
+    /// Put a token in an input slot, and schedule the node if it is ready.
+    ///
+    /// Merge is ready as soon as it gets a live input, other nodes once all
+    /// their inputs have arrived.
+    fn deliver(&mut self, node: usize, slot: usize, tag: Tag, token: Token) {
+        if self.merged.contains(&(node, tag)) {
+            return;
+        }
+        let merge = self.model.nodes[node].op_name == "Merge";
+        let ready = {
+            let inputs = self.model.nodes[node].inputs.len();
+            let slots = self.pending
+                .entry((node, tag))
+                .or_insert_with(|| vec![None; inputs]);
+            slots[slot] = Some(token);
+            slots.iter().all(|s| s.is_some()) || merge && slots.iter().any(Token::is_live)
+        };
+        if ready {
+            let slots = self.pending.remove(&(node, tag)).unwrap();
+            if merge {
+                self.merged.insert((node, tag));
+            }
+            self.ready.push_back((node, tag, slots));
+        }
+    }
+
+    fn fire(
+        &mut self,
+        state: &mut ModelState,
+        plan: &Plan,
+        node: usize,
+        tag: Tag,
+        slots: Vec<Option<Token>>,
+    ) -> Result<()> {
+        let model = self.model;
+        let n = &model.nodes[node];
+        let merge = n.op_name == "Merge";
+        let mut values = vec![];
+        let mut dead = false;
+        // index among the data inputs of the first live value, for Merge
+        let mut index = None;
+        let data_ports = n.inputs.iter().scan(0, |next, i| {
+            let ix = *next;
+            *next += i.1.is_some() as usize;
+            Some(ix)
+        });
+        for (slot, data_ix) in slots.into_iter().zip(data_ports) {
+            match slot {
+                Some(Token::Value(v)) => {
+                    index = index.or(Some(data_ix));
+                    values.push(v);
+                }
+                Some(Token::Dead) => dead = true,
+                _ => (),
+            }
+        }
+        let outputs: Outputs = if merge && !values.is_empty() {
+            let value = values.remove(0);
+            let inputs = vec![value.clone()];
+            state.before_eval(node, &inputs);
+            let start = Instant::now();
+            let index = index.unwrap_or(0) as i32;
+            let outputs = vec![value, Matrix::i32s(&[], &[index])?.into()];
+            state.after_eval(node, &outputs, start.elapsed());
+            Some(outputs.into_iter().map(Some).collect())
+        } else if dead || merge {
+            None
+        } else if n.op_name == "Switch" {
+            if values.len() != 2 {
+                Err(format!("Switch {} expects 2 inputs", n.name))?
+            }
+            state.before_eval(node, &values);
+            let start = Instant::now();
+            let pred = predicate(&values[1])?;
+            let value = values.swap_remove(0);
+            state.after_eval(node, ::std::slice::from_ref(&value), start.elapsed());
+            if pred {
+                Some(vec![None, Some(value)])
+            } else {
+                Some(vec![Some(value), None])
+            }
+        } else {
+            state.before_eval(node, &values);
+            let start = Instant::now();
+            let outputs = n.eval(values)?;
+            state.after_eval(node, &outputs, start.elapsed());
+            Some(outputs.into_iter().map(Some).collect())
+        };
+
+        let target = match (n.op.frame(), &*n.op_name) {
+            (Some((name, constant)), _) => {
+                let frame = self.child(tag, name);
+                if constant {
+                    for iteration in 1..self.frames[frame].iterations {
+                        self.send(node, (frame, iteration), &outputs)?;
+                    }
+                    self.frames[frame].constants.push((node, outputs.clone()));
+                }
+                (frame, 0)
+            }
+            (None, "Exit") if outputs.is_some() => self.frames[tag.0].parent,
+            (None, "NextIteration") if outputs.is_some() => {
+                let next = (tag.0, tag.1 + 1);
+                if next.1 >= self.frames[tag.0].iterations {
+                    self.frames[tag.0].iterations = next.1 + 1;
+                    let constants = self.frames[tag.0].constants.clone();
+                    for (enter, values) in constants {
+                        self.send(enter, next, &values)?;
+                    }
+                }
+                next
+            }
+            // dead tokens do not leave loop frames or iterations
+            (None, "Exit") | (None, "NextIteration") => return Ok(()),
+            _ => tag,
+        };
+
+        if target == ROOT
+            && (plan.targets.contains(&node) || !state.can_free(node))
+        {
+            if let Some(ref ports) = outputs {
+                if ports.iter().all(|p| p.is_some()) {
+                    state.outputs[node] = Some(ports.iter().cloned().map(Option::unwrap).collect());
+                }
+            }
+        }
+        self.send(node, target, &outputs)
+    }
+
+    /// Instance of frame `name` entered from the frame iteration `tag`.
+    fn child(&mut self, tag: Tag, name: &str) -> usize {
+        let frames = &mut self.frames;
+        *self.children
+            .entry((tag, name.to_string()))
+            .or_insert_with(|| {
+                frames.push(Frame {
+                    parent: tag,
+                    iterations: 1,
+                    constants: vec![],
+                });
+                frames.len() - 1
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {tfpb, Matrix, Model};
+    use tfpb::types::DataType::DT_FLOAT;
+
+    fn bin(name: &str, op: &str, a: &str, b: &str) -> tfpb::node_def::NodeDef {
+        tfpb::node()
+            .name(name)
+            .op(op)
+            .attr("T", DT_FLOAT)
+            .input(a)
+            .input(b)
+    }
+
+    fn konst(name: &str, value: f32) -> tfpb::node_def::NodeDef {
+        tfpb::node()
+            .name(name)
+            .op("Const")
+            .attr("value", tfpb::tensor_f32(vec![], vec![value]))
+    }
+
+    fn enter(name: &str, input: &str, constant: bool) -> tfpb::node_def::NodeDef {
+        tfpb::node()
+            .name(name)
+            .op("Enter")
+            .attr("frame_name", "loop")
+            .attr("is_constant", constant)
+            .input(input)
+    }
+
+    #[test]
+    fn cond() {
+        let graph = tfpb::graph()
+            .node(tfpb::node().name("x").op("Placeholder"))
+            .node(tfpb::node().name("limit").op("Placeholder"))
+            .node(konst("one", 1.0))
+            .node(bin("pred", "Less", "x", "limit"))
+            .node(bin("switch", "Switch", "x", "pred"))
+            .node(bin("f", "Sub", "switch:0", "one"))
+            .node(bin("t", "Add", "switch:1", "one"))
+            .node(bin("merge", "Merge", "f", "t"));
+        let model = Model::new(graph).unwrap();
+        let x = Matrix::f32s(&[], &[3.0]).unwrap();
+        for &(limit, value, index) in &[(5.0, 4.0, 1), (1.0, 2.0, 0)] {
+            let inputs = vec![
+                ("x", x.clone()),
+                ("limit", Matrix::f32s(&[], &[limit]).unwrap()),
+            ];
+            let result = model.run_with_names(inputs, "merge").unwrap();
+            assert_eq!(result[0], Matrix::f32s(&[], &[value]).unwrap());
+            assert_eq!(result[1], Matrix::i32s(&[], &[index]).unwrap());
+        }
+    }
+
+    #[test]
+    fn untaken_branch_is_not_computed() {
+        let graph = tfpb::graph()
+            .node(tfpb::node().name("x").op("Placeholder"))
+            .node(tfpb::node().name("pred").op("Placeholder"))
+            .node(tfpb::node().name("switch").op("Switch").input("x").input("pred"))
+            .node(tfpb::node().name("t").op("Identity").input("switch:1"));
+        let model = Model::new(graph).unwrap();
+        let inputs = vec![
+            ("x", Matrix::f32s(&[], &[3.0]).unwrap()),
+            ("pred", Matrix::bools(&[], &[false]).unwrap()),
+        ];
+        assert!(model.run_with_names(inputs, "t").is_err());
+    }
+
+    #[test]
+    fn while_loop() {
+        // i = 0; while i != n { i = i + 1 }
+        let graph = tfpb::graph()
+            .node(tfpb::node().name("n").op("Placeholder"))
+            .node(konst("zero", 0.0))
+            .node(konst("one", 1.0))
+            .node(enter("enter_i", "zero", false))
+            .node(enter("enter_n", "n", true))
+            .node(enter("enter_one", "one", true))
+            .node(bin("merge", "Merge", "enter_i", "next"))
+            .node(bin("differ", "NotEqual", "merge", "enter_n"))
+            .node(tfpb::node().name("cond").op("LoopCond").input("differ"))
+            .node(bin("switch", "Switch", "merge", "cond"))
+            .node(tfpb::node().name("exit").op("Exit").input("switch:0"))
+            .node(bin("add", "Add", "switch:1", "enter_one"))
+            .node(tfpb::node().name("next").op("NextIteration").input("add"));
+        let model = Model::new(graph).unwrap();
+        let n = model.node_id_by_name("n").unwrap();
+        let exit = model.node_id_by_name("exit").unwrap();
+        let mut state = model.state();
+        state.set_profiling(true);
+        let result = state
+            .run(vec![(n, Matrix::f32s(&[], &[5.0]).unwrap())], exit)
+            .unwrap();
+        assert_eq!(result[0], Matrix::f32s(&[], &[5.0]).unwrap());
+        let profile = state.profile().unwrap();
+        assert_eq!(profile.nodes.iter().filter(|n| n.op_name == "Add").count(), 5);
+        assert_eq!(profile.nodes.iter().filter(|n| n.op_name == "NotEqual").count(), 6);
+    }
+}
@@ -1,8 +1,13 @@
 //! error_chain generated types
+//!
+//! Kinds raised by an op while it is evaluated are created with an empty
+//! node name, which `Node::eval` fills in.
+
+use tfpb::types::DataType;
 
 error_chain!{
     foreign_links {
-        Image(::image::ImageError) #[cfg(features="image_ops")];
+        Image(::image::ImageError) #[cfg(feature = "image_ops")];
         Io(::std::io::Error);
         NdarrayShape(::ndarray::ShapeError);
         Protobuf(::protobuf::ProtobufError);
@@ -10,5 +15,90 @@ error_chain!{
     }
     errors {
         TFString {}
+        UnsupportedOp(node: String, op: String) {
+            description("unsupported op")
+            display("{}unimplemented operation {}", at(node, ""), op)
+        }
+        UnsupportedDatatype(node: String, op: String, datatype: DataType) {
+            description("unsupported datatype")
+            display("{}{} is not implemented for {:?}", at(node, ""), op, datatype)
+        }
+        DatatypeMismatch(node: String, op: String, expected: DataType, found: DataType) {
+            description("datatype mismatch")
+            display("{}expected {:?}, found {:?}", at(node, op), expected, found)
+        }
+        ShapeMismatch(node: String, op: String, expected: Vec<Option<usize>>, found: Vec<usize>) {
+            description("shape mismatch")
+            display("{}expected shape {}, found {:?}", at(node, op), dims(expected), found)
+        }
+        MissingFeed(node: String, op: String) {
+            description("missing feed")
+            display("{} {} is needed but was not fed", op, node)
+        }
+        PlanningCycle(node: String, op: String) {
+            description("planning cycle")
+            display("{}could not plan for node, it depends on itself", at(node, op))
+        }
+        MalformedAttribute(node: String, op: String, attr: String, reason: String) {
+            description("malformed attribute")
+            display("{}attribute {}: {}", at(node, op), attr, reason)
+        }
+        EvalFailed(node: String, op: String) {
+            description("node evaluation failed")
+            display("Evaluating node {} ({})", node, op)
+        }
+    }
+}
+
+/// Prefix for messages about a node, if it is known.
+fn at(node: &str, op: &str) -> String {
+    match (node.is_empty(), op.is_empty()) {
+        (true, _) => String::new(),
+        (false, true) => format!("Node {}: ", node),
+        (false, false) => format!("Node {} ({}): ", node, op),
+    }
+}
+
+fn dims(shape: &[Option<usize>]) -> String {
+    let dims: Vec<String> = shape
+        .iter()
+        .map(|d| d.map(|d| d.to_string()).unwrap_or("?".to_string()))
+        .collect();
+    format!("[{}]", dims.join(","))
+}
+
+impl Error {
+    /// Attach the name and op of the node being evaluated to an error its
+    /// op raised.
+    ///
+    /// The empty node fields of the kinds carrying them are filled in, so
+    /// these kinds can still be matched on. Other errors are chained under
+    /// an `EvalFailed`.
+    pub fn in_node(self, node: &str, op: &str) -> Error {
+        let Error(kind, state) = self;
+        let (n, o) = (node.to_string(), op.to_string());
+        let kind = match kind {
+            ErrorKind::UnsupportedOp(ref name, ref unsupported) if name.is_empty() => {
+                ErrorKind::UnsupportedOp(n, unsupported.clone())
+            }
+            ErrorKind::UnsupportedDatatype(ref name, ref unsupported, dt) if name.is_empty() => {
+                ErrorKind::UnsupportedDatatype(n, unsupported.clone(), dt)
+            }
+            ErrorKind::DatatypeMismatch(ref name, _, expected, found) if name.is_empty() => {
+                ErrorKind::DatatypeMismatch(n, o, expected, found)
+            }
+            ErrorKind::ShapeMismatch(ref name, _, ref expected, ref found) if name.is_empty() => {
+                ErrorKind::ShapeMismatch(n, o, expected.clone(), found.clone())
+            }
+            ErrorKind::MissingFeed(ref name, _) if name.is_empty() => ErrorKind::MissingFeed(n, o),
+            ErrorKind::PlanningCycle(ref name, _) if name.is_empty() => {
+                ErrorKind::PlanningCycle(n, o)
+            }
+            ErrorKind::MalformedAttribute(ref name, _, ref attr, ref reason) if name.is_empty() => {
+                ErrorKind::MalformedAttribute(n, o, attr.clone(), reason.clone())
+            }
+            kind => return Error::with_chain(Error(kind, state), ErrorKind::EvalFailed(n, o)),
+        };
+        Error(kind, state)
     }
 }
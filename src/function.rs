@@ -0,0 +1,398 @@
+//! Inlining of FunctionDef library calls.
+//!
+//! Graphs can wrap parts of their computation in functions from the graph
+//! library, called through `PartitionedCall`, `StatefulPartitionedCall` or
+//! an op named after the function. Calls are inlined at load time: the body
+//! nodes are copied into the graph under the name of the call node, with the
+//! function attributes substituted, and the call node itself becomes an
+//! `IdentityN` forwarding the function results.
+
+use std::collections::HashMap;
+
+use tfpb::attr_value::AttrValue;
+use tfpb::function::FunctionDef;
+use tfpb::graph::GraphDef;
+use tfpb::node_def::NodeDef;
+use tfpb::op_def::{OpDef, OpDef_ArgDef};
+use Result;
+
+/// Maximum nesting of function calls, to catch recursive functions.
+const MAX_DEPTH: usize = 64;
+
+type Attrs = HashMap<String, AttrValue>;
+
+/// Replace all function calls of a graph by the body of the functions.
+pub fn inline_functions(mut graph: GraphDef) -> Result<GraphDef> {
+    if graph.get_library().get_function().is_empty() {
+        return Ok(graph);
+    }
+    let library = graph.take_library();
+    let functions: HashMap<&str, &FunctionDef> = library
+        .get_function()
+        .iter()
+        .map(|f| (f.get_signature().get_name(), f))
+        .collect();
+    let mut nodes = vec![];
+    for node in graph.take_node().into_iter() {
+        inline_node(node, &functions, 0, &mut nodes)?;
+    }
+    graph.set_node(nodes.into());
+    Ok(graph)
+}
+
+fn inline_node(
+    node: NodeDef,
+    functions: &HashMap<&str, &FunctionDef>,
+    depth: usize,
+    nodes: &mut Vec<NodeDef>,
+) -> Result<()> {
+    let expanded = match called(&node, functions)? {
+        Some((function, attrs)) => {
+            if depth >= MAX_DEPTH {
+                Err(format!(
+                    "Function calls nested too deep in {}, is {} recursive?",
+                    node.get_name(),
+                    function.get_signature().get_name()
+                ))?
+            }
+            expand(&node, function, attrs, functions)?
+        }
+        None => {
+            nodes.push(node);
+            return Ok(());
+        }
+    };
+    for node in expanded {
+        inline_node(node, functions, depth + 1, nodes)?;
+    }
+    Ok(())
+}
+
+/// Function called by a node, with the attributes of the call.
+fn called<'a>(
+    node: &'a NodeDef,
+    functions: &HashMap<&str, &'a FunctionDef>,
+) -> Result<Option<(&'a FunctionDef, &'a Attrs)>> {
+    match node.get_op() {
+        "PartitionedCall" | "StatefulPartitionedCall" => {
+            let f = node.get_attr()
+                .get("f")
+                .ok_or_else(|| format!("Call {} has no function attr", node.get_name()))?
+                .get_func();
+            let function = functions.get(f.get_name()).ok_or_else(|| {
+                format!(
+                    "Call {}: function {} not found in graph library",
+                    node.get_name(),
+                    f.get_name()
+                )
+            })?;
+            Ok(Some((function, f.get_attr())))
+        }
+        op => Ok(functions.get(op).map(|&f| (f, node.get_attr()))),
+    }
+}
+
+/// Body of the function, renamed and bound to the call inputs, followed by
+/// the call node forwarding the results.
+fn expand(
+    call: &NodeDef,
+    function: &FunctionDef,
+    attrs: &Attrs,
+    functions: &HashMap<&str, &FunctionDef>,
+) -> Result<Vec<NodeDef>> {
+    let prefix = call.get_name();
+    let sig = function.get_signature();
+    let (control, data): (Vec<&String>, Vec<&String>) =
+        call.get_input().iter().partition(|i| i.starts_with('^'));
+    let mut data = data.into_iter();
+    let mut nodes = vec![];
+
+    for arg in sig.get_input_arg() {
+        let mut node = ::tfpb::node()
+            .name(format!("{}/{}", prefix, arg.get_name()))
+            .op("IdentityN");
+        for _ in 0..arg_len(arg, attrs, sig)? {
+            let input = data.next().ok_or_else(|| {
+                format!("Call {}: not enough inputs for {}", prefix, sig.get_name())
+            })?;
+            node = node.input(input);
+        }
+        for &c in &control {
+            node = node.input(c);
+        }
+        nodes.push(node);
+    }
+    if data.next().is_some() {
+        Err(format!("Call {}: too many inputs for {}", prefix, sig.get_name()))?
+    }
+
+    let body: HashMap<&str, &NodeDef> = function
+        .get_node_def()
+        .iter()
+        .map(|n| (n.get_name(), n))
+        .collect();
+    for original in function.get_node_def() {
+        let mut node = original.clone();
+        node.set_name(format!("{}/{}", prefix, original.get_name()));
+        let mut inputs = original
+            .get_input()
+            .iter()
+            .map(|i| body_input(prefix, i, &body, functions))
+            .collect::<Result<Vec<String>>>()?;
+        // sources of the body wait for the call control dependencies
+        if inputs.is_empty() {
+            inputs.extend(control.iter().map(|c| c.to_string()));
+        }
+        node.set_input(inputs.into());
+        for value in node.mut_attr().values_mut() {
+            substitute(value, attrs, sig)?;
+        }
+        nodes.push(node);
+    }
+
+    let mut node = ::tfpb::node().name(prefix).op("IdentityN");
+    for arg in sig.get_output_arg() {
+        let ret = function.get_ret().get(arg.get_name()).ok_or_else(|| {
+            format!("Function {} does not return {}", sig.get_name(), arg.get_name())
+        })?;
+        let ret = body_input(prefix, ret, &body, functions)?;
+        let (name, port) = ::parse_input_name(&ret)?;
+        for i in 0..arg_len(arg, attrs, sig)? {
+            node = node.input(format!("{}:{}", name, port + i));
+        }
+    }
+    nodes.push(node);
+    Ok(nodes)
+}
+
+/// Rename a reference to a function argument ("x", "x:1") or a body node
+/// output ("node:output_arg:index") into a graph input reference.
+fn body_input(
+    prefix: &str,
+    input: &str,
+    body: &HashMap<&str, &NodeDef>,
+    functions: &HashMap<&str, &FunctionDef>,
+) -> Result<String> {
+    if let Some(name) = input.strip_prefix('^') {
+        return Ok(format!("^{}/{}", prefix, name));
+    }
+    let parts: Vec<&str> = input.split(':').collect();
+    match parts.len() {
+        1 | 2 => Ok(format!("{}/{}", prefix, input)),
+        3 => {
+            let node = body.get(parts[0])
+                .ok_or_else(|| format!("Function body has no node {}", parts[0]))?;
+            let index: usize = parts[2]
+                .parse()
+                .map_err(|_| format!("Invalid input reference {}", input))?;
+            let offset = output_offset(node, parts[1], functions)?;
+            Ok(format!("{}/{}:{}", prefix, parts[0], offset + index))
+        }
+        _ => Err(format!("Invalid input reference {}", input))?,
+    }
+}
+
+/// First output port of the output arg `arg` of a node.
+///
+/// Without the op definitions, ports are only known for library functions
+/// and for the ops with several output args tfdeploy runs. Other ops are
+/// assumed to have a single output arg.
+fn output_offset(
+    node: &NodeDef,
+    arg: &str,
+    functions: &HashMap<&str, &FunctionDef>,
+) -> Result<usize> {
+    if let Some((function, attrs)) = called(node, functions)? {
+        let sig = function.get_signature();
+        let mut offset = 0;
+        for output in sig.get_output_arg() {
+            if output.get_name() == arg {
+                return Ok(offset);
+            }
+            offset += arg_len(output, attrs, sig)?;
+        }
+        // PartitionedCall has a single list output
+        return Ok(0);
+    }
+    Ok(match (node.get_op(), arg) {
+        ("Switch", "output_true") => 1,
+        ("Merge", "value_index") => 1,
+        ("TopKV2", "indices") => 1,
+        (op, arg) if op.starts_with("FusedBatchNorm") => match arg {
+            "batch_mean" => 1,
+            "batch_variance" => 2,
+            "reserve_space_1" => 3,
+            "reserve_space_2" => 4,
+            "reserve_space_3" => 5,
+            _ => 0,
+        },
+        _ => 0,
+    })
+}
+
+/// Number of tensors bound to an argument.
+fn arg_len(arg: &OpDef_ArgDef, attrs: &Attrs, sig: &OpDef) -> Result<usize> {
+    if !arg.get_number_attr().is_empty() {
+        Ok(lookup(arg.get_number_attr(), attrs, sig)?.get_i() as usize)
+    } else if !arg.get_type_list_attr().is_empty() {
+        Ok(lookup(arg.get_type_list_attr(), attrs, sig)?
+            .get_list()
+            .get_field_type()
+            .len())
+    } else {
+        Ok(1)
+    }
+}
+
+/// Value of a function attribute, as given by the call or by default.
+fn lookup<'a>(name: &str, attrs: &'a Attrs, sig: &'a OpDef) -> Result<&'a AttrValue> {
+    attrs
+        .get(name)
+        .or_else(|| {
+            sig.get_attr()
+                .iter()
+                .find(|a| a.get_name() == name && a.has_default_value())
+                .map(|a| a.get_default_value())
+        })
+        .ok_or_else(|| format!("Function {} needs attr {}", sig.get_name(), name).into())
+}
+
+/// Replace placeholders by the function attributes they refer to.
+fn substitute(value: &mut AttrValue, attrs: &Attrs, sig: &OpDef) -> Result<()> {
+    if value.has_placeholder() {
+        *value = lookup(value.get_placeholder(), attrs, sig)?.clone();
+    } else if value.has_func() {
+        for v in value.mut_func().mut_attr().values_mut() {
+            substitute(v, attrs, sig)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tfpb::attr_value::NameAttrList;
+    use tfpb::types::DataType::DT_FLOAT;
+    use {tfpb, Matrix, Model};
+
+    fn placeholder(name: &str) -> AttrValue {
+        let mut value = AttrValue::new();
+        value.set_placeholder(name.to_string());
+        value
+    }
+
+    fn arg(name: &str) -> OpDef_ArgDef {
+        let mut arg = OpDef_ArgDef::new();
+        arg.set_name(name.to_string());
+        arg.set_type_attr("T".to_string());
+        arg
+    }
+
+    /// y = x + 1
+    fn add_one() -> FunctionDef {
+        let mut f = FunctionDef::new();
+        f.mut_signature().set_name("add_one".to_string());
+        f.mut_signature().mut_input_arg().push(arg("x"));
+        f.mut_signature().mut_output_arg().push(arg("y"));
+        f.mut_node_def().push(
+            tfpb::node()
+                .name("one")
+                .op("Const")
+                .attr("value", tfpb::tensor_f32(vec![], vec![1.0])),
+        );
+        f.mut_node_def().push(
+            tfpb::node()
+                .name("add")
+                .op("Add")
+                .attr("T", placeholder("T"))
+                .input("x")
+                .input("one:output:0"),
+        );
+        f.mut_ret().insert("y".to_string(), "add:z:0".to_string());
+        f
+    }
+
+    /// y = add_one(add_one(x))
+    fn add_two() -> FunctionDef {
+        let mut f = FunctionDef::new();
+        f.mut_signature().set_name("add_two".to_string());
+        f.mut_signature().mut_input_arg().push(arg("x"));
+        f.mut_signature().mut_output_arg().push(arg("y"));
+        f.mut_node_def().push(
+            tfpb::node()
+                .name("a")
+                .op("add_one")
+                .attr("T", placeholder("T"))
+                .input("x"),
+        );
+        f.mut_node_def().push(
+            tfpb::node()
+                .name("b")
+                .op("add_one")
+                .attr("T", placeholder("T"))
+                .input("a:y:0"),
+        );
+        f.mut_ret().insert("y".to_string(), "b:y:0".to_string());
+        f
+    }
+
+    fn call(function: &str) -> AttrValue {
+        let mut f = NameAttrList::new();
+        f.set_name(function.to_string());
+        f.mut_attr().insert("T".to_string(), DT_FLOAT.into());
+        let mut value = AttrValue::new();
+        value.set_func(f);
+        value
+    }
+
+    fn graph(call: NodeDef) -> GraphDef {
+        let mut graph = tfpb::graph()
+            .node(tfpb::node().name("input").op("Placeholder"))
+            .node(call.name("call").input("input"))
+            .node(tfpb::node().name("output").op("Identity").input("call:0"));
+        graph.mut_library().mut_function().push(add_one());
+        graph.mut_library().mut_function().push(add_two());
+        graph
+    }
+
+    fn run(graph: GraphDef) -> Matrix {
+        let model = Model::new(graph).unwrap();
+        let input = Matrix::f32s(&[2], &[1.0, 2.0]).unwrap();
+        model
+            .run_with_names(vec![("input", input)], "output")
+            .unwrap()
+            .remove(0)
+    }
+
+    #[test]
+    fn partitioned_call() {
+        let graph = graph(tfpb::node().op("StatefulPartitionedCall").attr("f", call("add_one")));
+        assert_eq!(run(graph), Matrix::f32s(&[2], &[2.0, 3.0]).unwrap());
+    }
+
+    #[test]
+    fn nested_calls() {
+        let graph = graph(tfpb::node().op("PartitionedCall").attr("f", call("add_two")));
+        let inlined = inline_functions(graph.clone()).unwrap();
+        let add = inlined
+            .get_node()
+            .iter()
+            .find(|n| n.get_name() == "call/b/add")
+            .unwrap();
+        assert_eq!(add.get_input(), ["call/b/x", "call/b/one:0"]);
+        assert_eq!(run(graph), Matrix::f32s(&[2], &[3.0, 4.0]).unwrap());
+    }
+
+    #[test]
+    fn function_op() {
+        let graph = graph(tfpb::node().op("add_two").attr("T", DT_FLOAT));
+        assert_eq!(run(graph), Matrix::f32s(&[2], &[3.0, 4.0]).unwrap());
+    }
+
+    #[test]
+    fn missing_function() {
+        let graph = graph(tfpb::node().op("PartitionedCall").attr("f", call("nope")));
+        assert!(Model::new(graph).is_err());
+    }
+}
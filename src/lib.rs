@@ -33,7 +33,8 @@ extern crate bit_set;
 extern crate derive_new;
 #[macro_use]
 extern crate error_chain;
-#[cfg(features="image_ops")]
+extern crate half;
+#[cfg(feature = "image_ops")]
 extern crate image;
 #[allow(unused_imports)]
 #[macro_use]
@@ -44,17 +45,28 @@ extern crate ndarray;
 extern crate num_traits;
 extern crate protobuf;
 
+pub mod analyser;
 pub mod errors;
 pub mod tfpb;
 pub mod matrix;
 pub mod ops;
+pub mod optim;
+mod dataflow;
+mod function;
+mod parallel;
+pub mod profile;
+pub mod session;
+pub mod validation;
 
 use std::{fs, path, str};
 use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use ops::{Input, Op};
 pub use errors::*;
 
 pub use matrix::Matrix;
+pub use session::{Session, SessionPool};
 
 #[derive(Debug)]
 pub struct Node {
@@ -66,6 +78,13 @@ pub struct Node {
 }
 
 impl Node {
+    /// Evaluate the node op, attaching the node name and op to errors.
+    pub fn eval(&self, inputs: Vec<Input>) -> Result<Vec<Input>> {
+        self.op
+            .eval(inputs)
+            .map_err(|e| e.in_node(&self.name, &self.op_name))
+    }
+
     pub fn dump_eval_tree(&self, model: &Model) -> String {
         self._dump_eval_tree(model, 0, &mut HashSet::new())
     }
@@ -84,7 +103,7 @@ impl Node {
     }
 
     pub fn eval_order(&self, model: &Model) -> Result<Vec<usize>> {
-        Ok(Plan::for_nodes(model, &[self.id])?.order)
+        Ok(Plan::for_node(model, self.id)?.order())
     }
 }
 
@@ -93,19 +112,45 @@ pub fn for_path<P: AsRef<path::Path>>(p: P) -> Result<Model> {
     Model::for_path(p)
 }
 
+/// A compiled execution plan.
+///
+/// A plan is built for a fixed set of fed inputs and requested outputs. It
+/// stores the evaluation order, with each node input already resolved to a
+/// precursor output slot, and the values that can be freed after each step.
+/// Once built, it can be run any number of times on any state of its model.
 pub struct Plan {
-    order: Vec<usize>,
+    inputs: Vec<usize>,
+    targets: Vec<usize>,
+    steps: Vec<Step>,
+    /// Nodes to evaluate, when the model has control flow and needs to be
+    /// run by the dataflow executor instead of following the steps.
+    dataflow: Option<bit_set::BitSet>,
+}
+
+struct Step {
+    node: usize,
+    /// Precursor node and output port for each data input.
+    inputs: Vec<(usize, usize)>,
+    /// Values that are not needed anymore once this step has run.
+    free: Vec<usize>,
 }
 
 impl Plan {
     fn for_node(model: &Model, target: usize) -> Result<Plan> {
-        Self::for_nodes(model, &[target])
+        Self::for_nodes(model, &[], &[target])
     }
 
-    fn for_nodes(model: &Model, targets: &[usize]) -> Result<Plan> {
+    fn for_nodes(model: &Model, inputs: &[usize], targets: &[usize]) -> Result<Plan> {
+        if model.has_control_flow() {
+            return Ok(Self::for_dataflow(model, inputs, targets));
+        }
         let mut order: Vec<usize> = Vec::new();
         let mut done = bit_set::BitSet::with_capacity(model.nodes.len());
         let mut needed = bit_set::BitSet::with_capacity(model.nodes.len());
+        // fed nodes are leaves: their precursors do not need to be computed
+        for &i in inputs {
+            done.insert(i);
+        }
         for &t in targets {
             needed.insert(t);
         }
@@ -119,8 +164,8 @@ impl Plan {
                 for i in node.inputs.iter() {
                     if !done.contains(i.0) {
                         computable = false;
-                        done_something = true;
-                        needed.insert(i.0.clone());
+                        // only newly needed nodes are progress, else cycles never end
+                        done_something |= needed.insert(i.0);
                     }
                 }
                 if computable {
@@ -136,35 +181,136 @@ impl Plan {
         for &t in targets {
             if !done.contains(t) {
                 let node = &model.nodes[t];
-                Err(format!("Could not plan for node {}", node.name))?
+                Err(ErrorKind::PlanningCycle(node.name.clone(), node.op_name.clone()))?
+            }
+        }
+        let mut last_use = vec![None; model.nodes.len()];
+        for (ix, &n) in order.iter().enumerate() {
+            for i in &model.nodes[n].inputs {
+                if i.1.is_some() {
+                    last_use[i.0] = Some(ix);
+                }
+            }
+        }
+        let mut steps: Vec<Step> = order
+            .iter()
+            .map(|&n| Step {
+                node: n,
+                inputs: model.nodes[n]
+                    .inputs
+                    .iter()
+                    .filter_map(|&(prec, port)| port.map(|port| (prec, port)))
+                    .collect(),
+                free: vec![],
+            })
+            .collect();
+        for (n, last) in last_use.into_iter().enumerate() {
+            if let Some(last) = last {
+                if !targets.contains(&n) {
+                    steps[last].free.push(n);
+                }
             }
         }
-        Ok(Plan { order })
+        Ok(Plan {
+            inputs: inputs.to_vec(),
+            targets: targets.to_vec(),
+            steps,
+            dataflow: None,
+        })
+    }
+
+    /// Make sure all the placeholders the plan needs are fed.
+    fn check_feeds(&self, model: &Model) -> Result<()> {
+        for n in self.order() {
+            let node = &model.nodes[n];
+            if node.op_name == "Placeholder" {
+                Err(ErrorKind::MissingFeed(node.name.clone(), node.op_name.clone()))?
+            }
+        }
+        Ok(())
+    }
+
+    /// Nodes this plan expects to be fed, in order.
+    pub fn inputs(&self) -> &[usize] {
+        &self.inputs
+    }
+
+    /// Nodes this plan computes, in order.
+    pub fn targets(&self) -> &[usize] {
+        &self.targets
+    }
+
+    /// Nodes this plan evaluates, in order.
+    ///
+    /// Plans for models with control flow have no static order: their nodes
+    /// are listed by id.
+    pub fn order(&self) -> Vec<usize> {
+        match self.dataflow {
+            Some(ref nodes) => nodes.iter().collect(),
+            None => self.steps.iter().map(|s| s.node).collect(),
+        }
     }
 
+    /// Run the plan.
+    ///
+    /// Intermediate values are dropped as soon as their last consumer has
+    /// been computed, unless they are one of the plan targets or have been
+    /// marked for retention with `ModelState::retain`.
     pub fn run(&self, state: &mut ModelState) -> Result<()> {
-        for &n in &self.order {
-            if state.outputs[n].is_none() {
-                state.compute_one(n)?;
+        if self.dataflow.is_some() {
+            return self.run_dataflow(state);
+        }
+        for step in &self.steps {
+            if state.outputs[step.node].is_none() {
+                state.compute_step(step)?;
+            }
+            for &n in &step.free {
+                if state.can_free(n) {
+                    state.outputs[n] = None;
+                }
             }
         }
         Ok(())
     }
 }
 
+/// Split a tensorflow input reference ("node" or "node:port") in a node name
+/// and an output port.
+fn parse_input_name(input: &str) -> Result<(&str, usize)> {
+    match input.rfind(':') {
+        Some(ix) => {
+            let port = input[ix + 1..]
+                .parse()
+                .map_err(|_| format!("Invalid input reference {}", input))?;
+            Ok((&input[..ix], port))
+        }
+        None => Ok((input, 0)),
+    }
+}
+
 /// Model is Tfdeploy workhouse. It wraps a protobuf tensorflow model,
 /// and runs the inference interpreter.
 ///
 pub struct Model {
     nodes: Vec<Node>,
     nodes_by_name: HashMap<String, usize>,
+    validation: validation::Validation,
 }
 
 impl Model {
     pub fn new(graph: tfpb::graph::GraphDef) -> Result<Model> {
+        let graph = function::inline_functions(graph)?;
         let mut nodes = vec![];
-        let mut nodes_by_name: HashMap<String, usize> = HashMap::new();
+        // nodes may refer to nodes defined after them (loops in control
+        // flow graphs), so all names are registered first.
+        let nodes_by_name: HashMap<String, usize> = graph
+            .get_node()
+            .iter()
+            .enumerate()
+            .map(|(ix, pbnode)| (pbnode.get_name().to_string(), ix))
+            .collect();
         let op_builder = ops::OpBuilder::new();
+        let mut validation = validation::Validation::default();
         for pbnode in graph.get_node().iter() {
             let name = pbnode.get_name().to_string();
             let inputs: Vec<(usize, Option<usize>)> = pbnode
@@ -180,36 +326,74 @@ impl Model {
                             None,
                         )
                     } else {
+                        let (name, port) = parse_input_name(i)?;
                         (
                             nodes_by_name
-                                .get(i)
-                                .ok_or(format!("No node {} found", i))?
+                                .get(name)
+                                .ok_or(format!("No node {} found", name))?
                                 .clone(),
-                            Some(0usize),
+                            Some(port),
                         )
                     };
                     Ok((input.0.clone(), input.1))
                 })
                 .collect::<Result<Vec<_>>>()
                 .map_err(|e| format!("While building node {}, {}", name, e.description()))?;
+            let op = match op_builder.try_build(&pbnode) {
+                Ok(op) => op,
+                Err(ref e) if validation.record(&name, e) => op_builder.build(&pbnode)?,
+                Err(e) => match *e.kind() {
+                    ErrorKind::MalformedAttribute(..) => Err(e)?,
+                    _ => Err(e).chain_err(|| {
+                        format!("While building node {} ({})", name, pbnode.get_op())
+                    })?,
+                },
+            };
             let node = Node {
                 id: nodes.len(),
                 name: name.to_string(),
                 op_name: pbnode.get_op().to_string(),
                 inputs: inputs,
-                op: op_builder
-                    .build(&pbnode)
-                    .map_err(|e| format!("While building node {}, {}", name, e.description()))?,
+                op,
             };
-            nodes_by_name.insert(name, nodes.len());
             nodes.push(node)
         }
         Ok(Model {
             nodes,
             nodes_by_name,
+            validation,
         })
     }
 
+    /// Run the optimisation passes: constant folding, batch normalisation
+    /// folding and bias/Relu fusion.
+    ///
+    /// Optimised chains are computed from their inputs, bypassing their
+    /// intermediate nodes. These are kept and can still be fetched, but
+    /// feeding them would be ignored. `feeds` lists the nodes, besides the
+    /// placeholders, which will be fed: they are left out of the rewrites.
+    pub fn optimize(&mut self, feeds: &[usize]) -> Result<()> {
+        optim::fold_constants(self, feeds)?;
+        optim::fold_batch_norm(self, feeds)?;
+        optim::fuse_bias_relu(self, feeds)?;
+        Ok(())
+    }
+
+    /// Build a model, failing with `UnsupportedOp` or `UnsupportedDatatype`
+    /// if it uses unsupported ops or datatypes.
+    pub fn new_checked(graph: tfpb::graph::GraphDef) -> Result<Model> {
+        let model = Model::new(graph)?;
+        match model.validation.error() {
+            Some(e) => Err(e),
+            None => Ok(model),
+        }
+    }
+
+    /// Unsupported ops and datatypes found when loading the model.
+    pub fn validation(&self) -> &validation::Validation {
+        &self.validation
+    }
+
     pub fn node_id_by_name(&self, name: &str) -> Result<usize> {
         self.nodes_by_name
             .get(name)
@@ -218,10 +402,7 @@ impl Model {
     }
 
     pub fn state(&self) -> ModelState {
-        ModelState {
-            model: self,
-            outputs: vec![None; self.nodes.len()],
-        }
+        ModelState::with_model(ModelRef::Borrowed(self))
     }
 
     /// Load a Tensorflow protobul model from a file.
@@ -246,6 +427,58 @@ impl Model {
         Self::graphdef_for_reader(fs::File::open(p)?)
     }
 
+    /// Infer facts about the outputs of all nodes.
+    ///
+    /// `feeds` override the facts for the given nodes, typically
+    /// placeholders.
+    pub fn analyse(&self, feeds: &[(usize, analyser::TensorFact)]) -> analyser::Analysis {
+        analyser::analyse(self, feeds)
+    }
+
+    /// Does the model contain control flow ops (Switch, Merge, loops...) ?
+    pub fn has_control_flow(&self) -> bool {
+        self.nodes
+            .iter()
+            .any(|n| ops::control_flow::CONTROL_FLOW_OPS.contains(&&*n.op_name))
+    }
+
+    /// Nodes consuming the outputs of each node, through data or control
+    /// dependencies.
+    fn successors(&self) -> Vec<Vec<usize>> {
+        let mut successors = vec![vec![]; self.nodes.len()];
+        for node in &self.nodes {
+            for i in &node.inputs {
+                if !successors[i.0].contains(&node.id) {
+                    successors[i.0].push(node.id);
+                }
+            }
+        }
+        successors
+    }
+
+    /// Append a node to the model, returning its id.
+    fn add_node(
+        &mut self,
+        name: String,
+        op_name: &str,
+        op: Box<Op>,
+        inputs: Vec<(usize, Option<usize>)>,
+    ) -> Result<usize> {
+        if self.nodes_by_name.contains_key(&name) {
+            Err(format!("A node named {} already exists", name))?
+        }
+        let id = self.nodes.len();
+        self.nodes_by_name.insert(name.clone(), id);
+        self.nodes.push(Node {
+            id,
+            name,
+            op_name: op_name.to_string(),
+            inputs,
+            op,
+        });
+        Ok(id)
+    }
+
     pub fn node_names(&self) -> Vec<&str> {
         self.nodes.iter().map(|s| &*s.name).collect()
     }
@@ -259,39 +492,230 @@ impl Model {
         Plan::for_node(&self, node)
     }
 
+    pub fn plan_for(&self, nodes: &[usize]) -> Result<Plan> {
+        Plan::for_nodes(&self, &[], nodes)
+    }
+
+    /// Compile a reusable plan computing `outputs` from values fed to
+    /// `inputs`.
+    ///
+    /// Fed nodes are considered as leaves: the nodes they depend on are not
+    /// part of the plan. Placeholders needed by the plan must be fed.
+    pub fn plan(&self, inputs: &[usize], outputs: &[usize]) -> Result<Plan> {
+        let plan = Plan::for_nodes(&self, inputs, outputs)?;
+        plan.check_feeds(&self)?;
+        Ok(plan)
+    }
+
     pub fn run(&self, inputs: Vec<(usize, Matrix)>, output: usize) -> Result<Vec<Matrix>> {
         self.state().run(inputs, output)
     }
 
+    /// Run the network once, and fetch the values of several nodes.
+    pub fn run_multiple(
+        &self,
+        inputs: Vec<(usize, Matrix)>,
+        outputs: &[usize],
+    ) -> Result<Vec<Vec<Matrix>>> {
+        self.state().run_multiple(inputs, outputs)
+    }
+
     pub fn nodes(&self) -> &[Node] {
         &*self.nodes
     }
 
     pub fn run_with_names(&self, inputs: Vec<(&str, Matrix)>, output: &str) -> Result<Vec<Matrix>> {
-        let inputs = inputs
+        let inputs = self.inputs_by_name(inputs)?;
+        self.run(inputs, self.node_id_by_name(output)?)
+    }
+
+    pub fn run_multiple_with_names(
+        &self,
+        inputs: Vec<(&str, Matrix)>,
+        outputs: &[&str],
+    ) -> Result<Vec<Vec<Matrix>>> {
+        let inputs = self.inputs_by_name(inputs)?;
+        let outputs = outputs
+            .iter()
+            .map(|name| self.node_id_by_name(name))
+            .collect::<Result<Vec<usize>>>()?;
+        self.run_multiple(inputs, &outputs)
+    }
+
+    fn inputs_by_name(&self, inputs: Vec<(&str, Matrix)>) -> Result<Vec<(usize, Matrix)>> {
+        inputs
             .into_iter()
             .map(|(name, mat)| -> Result<(usize, Matrix)> {
                 Ok((self.node_id_by_name(name)?, mat))
             })
-            .collect::<Result<_>>()?;
-        self.run(inputs, self.node_id_by_name(output)?)
+            .collect()
+    }
+}
+
+/// Hook notified around each node evaluation.
+///
+/// Observers are registered on a ModelState with `add_observer`. They can be
+/// used to log shapes, compare intermediate values with another runtime, or
+/// capture activations.
+pub trait Observer: Send + Sync {
+    /// Called before evaluating `node`, with its input values.
+    fn before(&mut self, _node: &Node, _inputs: &[Input]) {}
+
+    /// Called once `node` has been evaluated, with its output values.
+    fn after(&mut self, _node: &Node, _outputs: &[Input]) {}
+}
+
+/// Either a borrowed or a shared reference to a Model.
+#[derive(Clone)]
+enum ModelRef<'a> {
+    Borrowed(&'a Model),
+    Shared(Arc<Model>),
+}
+
+impl<'a> ::std::ops::Deref for ModelRef<'a> {
+    type Target = Model;
+    fn deref(&self) -> &Model {
+        match self {
+            &ModelRef::Borrowed(m) => m,
+            &ModelRef::Shared(ref m) => m.as_ref(),
+        }
     }
 }
 
 pub struct ModelState<'a> {
-    model: &'a Model,
+    model: ModelRef<'a>,
     pub outputs: Vec<Option<Vec<Input>>>,
+    retained: bit_set::BitSet,
+    fed: bit_set::BitSet,
+    /// Successors of each node, when running incrementally.
+    successors: Option<Vec<Vec<usize>>>,
+    profile: Option<profile::Profile>,
+    observers: Vec<Box<Observer>>,
 }
 
 impl<'a> ModelState<'a> {
+    fn with_model(model: ModelRef<'a>) -> ModelState<'a> {
+        let nodes = model.nodes.len();
+        ModelState {
+            model,
+            outputs: vec![None; nodes],
+            retained: bit_set::BitSet::new(),
+            fed: bit_set::BitSet::new(),
+            successors: None,
+            profile: None,
+            observers: vec![],
+        }
+    }
+
     /// Reset internal state.
+    ///
+    /// Computed values and profiling records are cleared, but the set of
+    /// retained nodes is kept.
     pub fn reset(&mut self) -> Result<()> {
-        self.outputs = vec![None; self.model.nodes.len()];
+        for output in self.outputs.iter_mut() {
+            *output = None;
+        }
+        self.fed.clear();
+        if let Some(ref mut profile) = self.profile {
+            profile.clear();
+        }
         Ok(())
     }
 
+    /// Bring the state back to what it was when created: on top of `reset`,
+    /// retained nodes and observers are dropped, and incremental evaluation
+    /// and profiling are switched off.
+    pub fn clear(&mut self) -> Result<()> {
+        self.reset()?;
+        self.retained.clear();
+        self.observers.clear();
+        self.successors = None;
+        self.profile = None;
+        Ok(())
+    }
+
+    /// Switch per-node profiling on or off.
+    pub fn set_profiling(&mut self, profiling: bool) {
+        self.profile = if profiling {
+            Some(profile::Profile::default())
+        } else {
+            None
+        };
+    }
+
+    /// Profiling records of the nodes evaluated since the last reset.
+    pub fn profile(&self) -> Option<&profile::Profile> {
+        self.profile.as_ref()
+    }
+
+    /// Switch incremental evaluation on or off.
+    ///
+    /// In incremental mode, runs do not reset the state and all computed
+    /// values are kept. Feeding a new value to a node only invalidates the
+    /// nodes depending on it, so the next run only recomputes these.
+    pub fn set_incremental(&mut self, incremental: bool) {
+        self.successors = if incremental {
+            Some(self.model.successors())
+        } else {
+            None
+        };
+    }
+
+    pub fn is_incremental(&self) -> bool {
+        self.successors.is_some()
+    }
+
+    /// Register an observer, to be notified of every node evaluation.
+    pub fn add_observer(&mut self, observer: Box<Observer>) {
+        self.observers.push(observer);
+    }
+
+    /// Unregister all observers.
+    pub fn clear_observers(&mut self) -> Vec<Box<Observer>> {
+        ::std::mem::replace(&mut self.observers, vec![])
+    }
+
+    /// Forget the values of all the nodes depending on `id`, except for the
+    /// fed ones.
+    fn invalidate_successors(&mut self, id: usize) {
+        let successors = match self.successors {
+            Some(ref successors) => successors,
+            None => return,
+        };
+        let mut todo = vec![id];
+        let mut seen = bit_set::BitSet::with_capacity(self.outputs.len());
+        while let Some(n) = todo.pop() {
+            for &s in &successors[n] {
+                if seen.insert(s) && !self.fed.contains(s) {
+                    self.outputs[s] = None;
+                    todo.push(s);
+                }
+            }
+        }
+    }
+
+    fn can_free(&self, id: usize) -> bool {
+        !self.retained.contains(id) && !self.is_incremental()
+    }
+
+    /// Keep the value of a node in the state once its consumers have been
+    /// computed, instead of freeing it.
+    pub fn retain(&mut self, id: usize) {
+        self.retained.insert(id);
+    }
+
+    /// Cancel a previous `retain`.
+    pub fn release(&mut self, id: usize) {
+        self.retained.remove(id);
+    }
+
     pub fn set_outputs(&mut self, id: usize, values: Vec<Matrix>) -> Result<()> {
-        self.outputs[id] = Some(values.into_iter().map(Input::Owned).collect());
+        let values: Vec<Input> = values.into_iter().map(Input::Owned).collect();
+        if self.outputs[id].as_ref() != Some(&values) {
+            self.invalidate_successors(id);
+        }
+        self.outputs[id] = Some(values);
+        self.fed.insert(id);
         Ok(())
     }
 
@@ -300,6 +724,65 @@ impl<'a> ModelState<'a> {
     }
 
     pub fn compute_one(&mut self, node: usize) -> Result<()> {
+        let inputs = self.inputs_for(node)?;
+        self.eval_node(node, inputs)
+    }
+
+    /// Compute a plan step, its inputs being already resolved.
+    fn compute_step(&mut self, step: &Step) -> Result<()> {
+        let inputs = self.step_inputs(step)?;
+        self.eval_node(step.node, inputs)
+    }
+
+    fn step_inputs(&self, step: &Step) -> Result<Vec<Input>> {
+        step.inputs
+            .iter()
+            .map(|&(prec, port)| {
+                self.outputs[prec]
+                    .as_ref()
+                    .and_then(|outputs| outputs.get(port))
+                    .cloned()
+                    .ok_or_else(|| {
+                        format!(
+                            "Computing {}, output {} of precursor {} not available",
+                            self.model.nodes[step.node].name, port, self.model.nodes[prec].name
+                        ).into()
+                    })
+            })
+            .collect()
+    }
+
+    fn eval_node(&mut self, node: usize, inputs: Vec<Input>) -> Result<()> {
+        self.before_eval(node, &inputs);
+        let start = Instant::now();
+        let outputs = self.model.nodes[node].eval(inputs)?;
+        self.store(node, outputs, start.elapsed());
+        Ok(())
+    }
+
+    fn before_eval(&mut self, node: usize, inputs: &[Input]) {
+        for observer in self.observers.iter_mut() {
+            observer.before(&self.model.nodes[node], inputs);
+        }
+    }
+
+    fn after_eval(&mut self, node: usize, outputs: &[Input], duration: Duration) {
+        if let Some(ref mut profile) = self.profile {
+            profile.record(&self.model.nodes[node], duration, outputs);
+        }
+        for observer in self.observers.iter_mut() {
+            observer.after(&self.model.nodes[node], outputs);
+        }
+    }
+
+    /// Store the result of a node evaluation.
+    fn store(&mut self, node: usize, outputs: Vec<Input>, duration: Duration) {
+        self.after_eval(node, &outputs, duration);
+        self.outputs[node] = Some(outputs);
+    }
+
+    /// Gather the input values of a node from the values of its precursors.
+    fn inputs_for(&self, node: usize) -> Result<Vec<Input>> {
         let node: &Node = &self.model.nodes[node];
         let mut inputs: Vec<Input> = vec![];
         for i in &node.inputs {
@@ -308,11 +791,18 @@ impl<'a> ModelState<'a> {
                 "Computing {}, precursor {} not done:",
                 node.name, prec_node.name
             ))?;
-            inputs.push(prec[i.1.ok_or("no output found")?].clone().into())
+            // control dependencies only constrain the evaluation order
+            let port = match i.1 {
+                Some(port) => port,
+                None => continue,
+            };
+            let value = prec.get(port).ok_or(format!(
+                "Computing {}, precursor {} has no output {}",
+                node.name, prec_node.name, port
+            ))?;
+            inputs.push(value.clone())
         }
-        let outputs = node.op.eval(inputs)?;
-        self.outputs[node.id] = Some(outputs);
-        Ok(())
+        Ok(inputs)
     }
 
     pub fn take_by_name(&mut self, name: &str) -> Result<Vec<Matrix>> {
@@ -333,15 +823,552 @@ impl<'a> ModelState<'a> {
     ///
     /// Clears the internal state.
     pub fn run(&mut self, inputs: Vec<(usize, Matrix)>, output: usize) -> Result<Vec<Matrix>> {
-        self.reset()?;
-        for input in inputs {
-            self.set_value(input.0, input.1)?;
+        Ok(self.run_multiple(inputs, &[output])?.remove(0))
+    }
+
+    /// Run the network for several outputs at once.
+    ///
+    /// All outputs are computed by a single evaluation of the graph, and are
+    /// returned in the order they were requested.
+    pub fn run_multiple(
+        &mut self,
+        inputs: Vec<(usize, Matrix)>,
+        outputs: &[usize],
+    ) -> Result<Vec<Vec<Matrix>>> {
+        let (ids, values): (Vec<usize>, Vec<Matrix>) = inputs.into_iter().unzip();
+        let plan = self.model.plan(&ids, outputs)?;
+        self.run_plan(&plan, values)
+    }
+
+    /// Run a compiled plan.
+    ///
+    /// `inputs` are the values for the plan inputs, in the same order. Returns
+    /// the values of the plan targets.
+    pub fn run_plan(&mut self, plan: &Plan, inputs: Vec<Matrix>) -> Result<Vec<Vec<Matrix>>> {
+        if inputs.len() != plan.inputs.len() {
+            Err(format!(
+                "Plan expects {} inputs, got {}",
+                plan.inputs.len(),
+                inputs.len()
+            ))?
+        }
+        if !self.is_incremental() {
+            self.reset()?;
         }
-        Plan::for_node(self.model, output)?.run(self)?;
-        Ok(self.take(output)?)
+        for (&id, value) in plan.inputs.iter().zip(inputs.into_iter()) {
+            self.set_value(id, value)?;
+        }
+        plan.run(self)?;
+        let outputs = &plan.targets;
+        outputs
+            .iter()
+            .enumerate()
+            .map(|(ix, &id)| {
+                if self.is_incremental() || outputs[ix + 1..].contains(&id) {
+                    Ok(self.outputs[id]
+                        .as_ref()
+                        .ok_or("Value is not computed")?
+                        .iter()
+                        .map(|i| i.as_matrix().clone())
+                        .collect())
+                } else {
+                    self.take(id)
+                }
+            })
+            .collect()
     }
 
     pub fn model(&self) -> &Model {
-        self.model
+        &self.model
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_input_names() {
+        assert_eq!(parse_input_name("split").unwrap(), ("split", 0));
+        assert_eq!(parse_input_name("split:1").unwrap(), ("split", 1));
+        assert_eq!(parse_input_name("a/b:12").unwrap(), ("a/b", 12));
+        assert!(parse_input_name("split:a").is_err());
+    }
+
+    #[test]
+    fn malformed_graphs() {
+        use tfpb::types::DataType::*;
+        let mut complex = tfpb::tensor_f32(vec![1], vec![]);
+        complex.set_dtype(DT_COMPLEX64);
+        let graph = tfpb::graph().node(tfpb::node().name("s").op("Const").attr("value", complex));
+        assert!(Model::new(graph).is_err());
+
+        let long = tfpb::tensor_f32(vec![2, 2], vec![1.0, 2.0, 3.0, 4.0, 5.0]);
+        let graph = tfpb::graph().node(tfpb::node().name("c").op("Const").attr("value", long));
+        assert!(Model::new(graph).is_err());
+
+        for dim in &[-1, 1 << 40] {
+            let mut huge = tfpb::tensor_f32(vec![1 << 40, 1 << 40], vec![1.0]);
+            huge.mut_tensor_shape().mut_dim()[0].size = *dim;
+            let graph = tfpb::graph().node(tfpb::node().name("c").op("Const").attr("value", huge));
+            match *Model::new(graph).err().unwrap().kind() {
+                ErrorKind::MalformedAttribute(ref node, _, ref attr, _) => {
+                    assert_eq!((&**node, &**attr), ("c", "value"))
+                }
+                ref kind => panic!("unexpected error {:?}", kind),
+            }
+        }
+
+        let graph = tfpb::graph()
+            .node(tfpb::node().name("a").op("Placeholder"))
+            .node(tfpb::node().name("axis").op("Placeholder"))
+            .node(
+                tfpb::node()
+                    .name("concat")
+                    .op("ConcatV2")
+                    .attr("N", 2)
+                    .input("a")
+                    .input("a")
+                    .input("axis"),
+            );
+        let model = Model::new(graph).unwrap();
+        let a = Matrix::i32s(&[2], &[1, 2]).unwrap();
+        let result = model.run_with_names(
+            vec![("a", a.clone()), ("axis", Matrix::i32s(&[1], &[-1]).unwrap())],
+            "concat",
+        );
+        assert_eq!(result.unwrap()[0], Matrix::i32s(&[4], &[1, 2, 1, 2]).unwrap());
+        let no_axis = Matrix::i32s(&[0], &[]).unwrap();
+        let err = model
+            .run_with_names(vec![("a", a), ("axis", no_axis)], "concat")
+            .unwrap_err();
+        match *err.kind() {
+            ErrorKind::EvalFailed(ref node, ref op) => {
+                assert_eq!((&**node, &**op), ("concat", "ConcatV2"))
+            }
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+        let floats = Matrix::f32s(&[1], &[0.0]).unwrap();
+        let axis = Matrix::f32s(&[1], &[0.0]).unwrap();
+        assert!(
+            model
+                .run_with_names(vec![("a", floats), ("axis", axis)], "concat")
+                .is_err()
+        );
+
+        let mut state = model.state();
+        assert!(state.compute_one(0).is_err());
+    }
+
+    #[test]
+    fn strings() {
+        use tfpb::types::DataType::*;
+        let words = vec![b"foo".to_vec(), vec![], vec![0xff, 0], b"bar".to_vec()];
+        let words = Matrix::strings(&[2, 2], &words).unwrap();
+        let pb = words.to_pb().unwrap();
+        assert_eq!(pb.get_dtype(), DT_STRING);
+        assert_eq!(Matrix::from_pb(&pb).unwrap(), words);
+
+        let i32s = |name: &str, shape: Vec<usize>, values: &[i32]| {
+            let value = Matrix::i32s(&shape, values).unwrap().to_pb().unwrap();
+            tfpb::node().name(name).op("Const").attr("value", value)
+        };
+        let graph = tfpb::graph()
+            .node(tfpb::node().name("words").op("Const").attr("value", pb))
+            .node(tfpb::node().name("more").op("Placeholder").attr("dtype", DT_STRING))
+            .node(tfpb::node().name("id").op("Identity").input("more"))
+            .node(i32s("axis", vec![], &[0]))
+            .node(i32s("dims", vec![1], &[-1]))
+            .node(i32s("indices", vec![2], &[4, 1]))
+            .node(
+                tfpb::node()
+                    .name("concat")
+                    .op("ConcatV2")
+                    .attr("N", 2)
+                    .input("words")
+                    .input("id")
+                    .input("axis"),
+            )
+            .node(tfpb::node().name("flat").op("Reshape").input("concat").input("dims"))
+            .node(
+                tfpb::node()
+                    .name("gather")
+                    .op("Gather")
+                    .attr("Tparams", DT_STRING)
+                    .input("flat")
+                    .input("indices"),
+            )
+            .node(
+                tfpb::node()
+                    .name("pack")
+                    .op("Pack")
+                    .attr("T", DT_STRING)
+                    .attr("axis", 1)
+                    .input("gather")
+                    .input("gather"),
+            );
+        let model = Model::new(graph).unwrap();
+        let more = Matrix::strings(&[1, 2], &[b"baz".to_vec(), b"qux".to_vec()]).unwrap();
+        let flat = model.run_with_names(vec![("more", more.clone())], "flat").unwrap();
+        assert_eq!(flat[0].shape(), &[6]);
+        let pack = model.run_with_names(vec![("more", more)], "pack").unwrap();
+        let expected = vec![b"baz".to_vec(), b"baz".to_vec(), vec![], vec![]];
+        assert_eq!(pack[0], Matrix::strings(&[2, 2], &expected).unwrap());
+    }
+
+    #[test]
+    fn dtypes() {
+        use half::f16;
+        use tfpb::types::DataType::*;
+        let halves: Vec<f16> = [1.0f32, -0.5, 2.0].iter().map(|&x| f16::from_f32(x)).collect();
+        let matrices = vec![
+            Matrix::i64s(&[2], &[1 << 40, -1]).unwrap(),
+            Matrix::i16s(&[2], &[-300, 300]).unwrap(),
+            Matrix::u16s(&[2], &[0, 65535]).unwrap(),
+            Matrix::f16s(&[3], &halves).unwrap(),
+        ];
+        for m in matrices {
+            let pb = m.to_pb().unwrap();
+            assert_eq!(pb.get_dtype(), m.datatype());
+            assert_eq!(Matrix::from_pb(&pb).unwrap(), m);
+        }
+
+        let value = |name: &str, m: Matrix| {
+            tfpb::node().name(name).op("Const").attr("value", m.to_pb().unwrap())
+        };
+        let graph = tfpb::graph()
+            .node(tfpb::node().name("halves").op("Placeholder"))
+            .node(value("table", Matrix::i64s(&[3], &[10, 20, 30]).unwrap()))
+            .node(
+                tfpb::node()
+                    .name("add")
+                    .op("Add")
+                    .attr("T", DT_HALF)
+                    .input("halves")
+                    .input("halves"),
+            )
+            .node(tfpb::node().name("shape").op("Shape").attr("out_type", DT_INT64).input("add"))
+            .node(
+                tfpb::node()
+                    .name("gather")
+                    .op("Gather")
+                    .attr("Tparams", DT_INT64)
+                    .input("table")
+                    .input("shape"),
+            );
+        let model = Model::new(graph).unwrap();
+        let halves = Matrix::f16s(&[2], &halves[..2]).unwrap();
+        let add = model.run_with_names(vec![("halves", halves.clone())], "add").unwrap();
+        let expected: Vec<f16> = [2.0f32, -1.0].iter().map(|&x| f16::from_f32(x)).collect();
+        assert_eq!(add[0], Matrix::f16s(&[2], &expected).unwrap());
+        let gather = model.run_with_names(vec![("halves", halves)], "gather").unwrap();
+        assert_eq!(gather[0], Matrix::i64s(&[1], &[30]).unwrap());
+    }
+
+    #[test]
+    fn error_kinds() {
+        use tfpb::types::DataType::*;
+        let graph = tfpb::graph()
+            .node(tfpb::node().name("a").op("Placeholder"))
+            .node(tfpb::node().name("b").op("Placeholder"))
+            .node(
+                tfpb::node()
+                    .name("add")
+                    .op("Add")
+                    .attr("T", DT_FLOAT)
+                    .input("a")
+                    .input("b"),
+            );
+        let model = Model::new(graph).unwrap();
+        let a = Matrix::f32s(&[2], &[1.0, 2.0]).unwrap();
+        let run = |b: Matrix| {
+            model
+                .run_with_names(vec![("a", a.clone()), ("b", b)], "add")
+                .unwrap_err()
+        };
+        match *run(Matrix::i32s(&[2], &[1, 2]).unwrap()).kind() {
+            ErrorKind::DatatypeMismatch(ref node, ref op, DT_FLOAT, DT_INT32) => {
+                assert_eq!((&**node, &**op), ("add", "Add"))
+            }
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+        match *run(Matrix::f32s(&[3], &[1.0, 2.0, 3.0]).unwrap()).kind() {
+            ErrorKind::ShapeMismatch(ref node, _, ref expected, ref found) => {
+                assert_eq!(node, "add");
+                assert_eq!((expected, found), (&vec![Some(2)], &vec![3]));
+            }
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+        let mut state = model.state();
+        match *state.compute_one(0).unwrap_err().kind() {
+            ErrorKind::MissingFeed(ref node, ref op) => {
+                assert_eq!((&**node, &**op), ("a", "Placeholder"))
+            }
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+
+        let graph = tfpb::graph().node(tfpb::node().name("add").op("Add"));
+        match *Model::new(graph).err().unwrap().kind() {
+            ErrorKind::MalformedAttribute(ref node, ref op, ref attr, _) => {
+                assert_eq!((&**node, &**op, &**attr), ("add", "Add", "T"))
+            }
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+
+        let graph = tfpb::graph()
+            .node(tfpb::node().name("a").op("Placeholder"))
+            .node(tfpb::node().name("x").op("Identity").input("a").input("y"))
+            .node(tfpb::node().name("y").op("Identity").input("x"));
+        let model = Model::new(graph).unwrap();
+        match *model.plan_for_one(2).err().unwrap().kind() {
+            ErrorKind::PlanningCycle(_, ref op) => assert_eq!(op, "Identity"),
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+        let a = Matrix::f32s(&[1], &[1.0]).unwrap();
+        match *model.run_with_names(vec![("a", a)], "y").unwrap_err().kind() {
+            ErrorKind::PlanningCycle(ref node, ref op) => {
+                assert!(!node.is_empty() && op == "Identity")
+            }
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+
+        let raised = ErrorKind::MalformedAttribute("".into(), "".into(), "T".into(), "bad".into());
+        match *Error::from(raised).in_node("add", "Add").kind() {
+            ErrorKind::MalformedAttribute(ref node, ref op, ref attr, _) => {
+                assert_eq!((&**node, &**op, &**attr), ("add", "Add", "T"))
+            }
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn output_ports() {
+        use tfpb::types::DataType::DT_FLOAT;
+        let konst = |name: &str, v: f32| {
+            tfpb::node()
+                .name(name)
+                .op("Const")
+                .attr("dtype", DT_FLOAT)
+                .attr("value", tfpb::tensor_f32(vec![1], vec![v]))
+        };
+        let graph = tfpb::graph()
+            .node(konst("a", 1.0))
+            .node(konst("b", 2.0))
+            .node(tfpb::node().name("both").op("Identity").input("a").input("b"))
+            .node(
+                tfpb::node()
+                    .name("sum")
+                    .op("Add")
+                    .attr("T", DT_FLOAT)
+                    .input("both:1")
+                    .input("both:1"),
+            );
+        let model = Model::new(graph).unwrap();
+        let sum = model.node_id_by_name("sum").unwrap();
+        assert_eq!(model.nodes()[sum].inputs, vec![(2, Some(1)), (2, Some(1))]);
+        let result = model.run(vec![], sum).unwrap();
+        assert_eq!(result[0], Matrix::f32s(&[1], &[4.0]).unwrap());
+    }
+
+    #[test]
+    fn control_dependencies() {
+        use tfpb::types::DataType::DT_FLOAT;
+        let graph = tfpb::graph()
+            .node(
+                tfpb::node()
+                    .name("a")
+                    .op("Const")
+                    .attr("dtype", DT_FLOAT)
+                    .attr("value", tfpb::tensor_f32(vec![1], vec![1.0])),
+            )
+            .node(tfpb::node().name("init").op("NoOp"))
+            .node(
+                tfpb::node()
+                    .name("true")
+                    .op("Const")
+                    .attr("value", Matrix::bools(&[], &[true]).unwrap().to_pb().unwrap()),
+            )
+            .node(tfpb::node().name("check").op("Assert").input("true").input("a"))
+            .node(
+                tfpb::node()
+                    .name("id")
+                    .op("Identity")
+                    .input("a")
+                    .input("^init")
+                    .input("^check"),
+            );
+        let model = Model::new(graph).unwrap();
+        let id = model.node_id_by_name("id").unwrap();
+        assert_eq!(model.nodes()[id].inputs, vec![(0, Some(0)), (1, None), (3, None)]);
+        let order = model.nodes()[id].eval_order(&model).unwrap();
+        assert!(order.contains(&1) && order.contains(&3));
+        let result = model.run(vec![], id).unwrap();
+        assert_eq!(result, vec![Matrix::f32s(&[1], &[1.0]).unwrap()]);
+    }
+
+    #[test]
+    fn free_intermediates() {
+        let graph = tfpb::graph()
+            .node(tfpb::node().name("a").op("Placeholder"))
+            .node(tfpb::node().name("b").op("Identity").input("a"))
+            .node(tfpb::node().name("c").op("Identity").input("b"))
+            .node(tfpb::node().name("d").op("Identity").input("c"));
+        let model = Model::new(graph).unwrap();
+        let plan = model.plan(&[0], &[2, 3]).unwrap();
+        let a = Matrix::f32s(&[1], &[1.0]).unwrap();
+
+        let mut state = model.state();
+        state.set_value(0, a.clone()).unwrap();
+        plan.run(&mut state).unwrap();
+        assert!(state.outputs[0].is_none());
+        assert!(state.outputs[1].is_none());
+        assert!(state.outputs[2].is_some());
+        assert!(state.outputs[3].is_some());
+
+        state.reset().unwrap();
+        state.retain(1);
+        state.set_value(0, a).unwrap();
+        plan.run(&mut state).unwrap();
+        assert!(state.outputs[0].is_none());
+        assert!(state.outputs[1].is_some());
+    }
+
+    #[test]
+    fn compiled_plan() {
+        let model = for_path("tests/plus3.pb").unwrap();
+        let input = model.node_id_by_name("input").unwrap();
+        let output = model.node_id_by_name("output").unwrap();
+        let plan = model.plan(&[input], &[output]).unwrap();
+        assert_eq!(plan.order(), vec![1, 2]);
+        assert_eq!(plan.steps[1].inputs, vec![(0, 0), (1, 0)]);
+        assert_eq!(plan.steps[1].free, vec![0, 1]);
+        let mut state = model.state();
+        for i in 0..3 {
+            let value = Matrix::f32s(&[1], &[i as f32]).unwrap();
+            let result = state.run_plan(&plan, vec![value.clone()]).unwrap();
+            assert_eq!(result, vec![model.run(vec![(input, value)], output).unwrap()]);
+        }
+        assert!(state.run_plan(&plan, vec![]).is_err());
+    }
+
+    #[test]
+    fn feed_intermediate_node() {
+        let graph = tfpb::graph()
+            .node(tfpb::node().name("image").op("Placeholder"))
+            .node(tfpb::node().name("decoded").op("Identity").input("image"))
+            .node(tfpb::node().name("input").op("Placeholder"))
+            .node(
+                tfpb::node()
+                    .name("output")
+                    .op("Identity")
+                    .input("decoded")
+                    .input("input"),
+            );
+        let model = Model::new(graph).unwrap();
+        let value = Matrix::f32s(&[1], &[1.0]).unwrap();
+
+        let plan = model.plan(&[1, 2], &[3]).unwrap();
+        assert_eq!(plan.order(), vec![3]);
+        let result = model
+            .run_multiple(vec![(1, value.clone()), (2, value.clone())], &[3])
+            .unwrap();
+        assert_eq!(result[0], vec![value.clone(), value.clone()]);
+
+        let err = model.run(vec![(1, value)], 3).unwrap_err();
+        assert_eq!(err.to_string(), "Placeholder input is needed but was not fed");
+        match *err.kind() {
+            ErrorKind::MissingFeed(ref node, _) => assert_eq!(node, "input"),
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn incremental() {
+        let graph = tfpb::graph()
+            .node(tfpb::node().name("context").op("Placeholder"))
+            .node(tfpb::node().name("embedding").op("Identity").input("context"))
+            .node(tfpb::node().name("input").op("Placeholder"))
+            .node(
+                tfpb::node()
+                    .name("output")
+                    .op("Add")
+                    .attr("T", tfpb::types::DataType::DT_FLOAT)
+                    .input("embedding")
+                    .input("input"),
+            );
+        let model = Model::new(graph).unwrap();
+        let one = Matrix::f32s(&[1], &[1.0]).unwrap();
+        let two = Matrix::f32s(&[1], &[2.0]).unwrap();
+        let mut state = model.state();
+        state.set_incremental(true);
+
+        let result = state.run(vec![(0, one.clone()), (2, one.clone())], 3).unwrap();
+        assert_eq!(result, vec![two.clone()]);
+        assert!(state.outputs[1].is_some());
+
+        state.set_value(2, two.clone()).unwrap();
+        assert!(state.outputs[1].is_some());
+        assert!(state.outputs[3].is_none());
+        let result = state.run(vec![(0, one.clone()), (2, two.clone())], 3).unwrap();
+        assert_eq!(result, vec![Matrix::f32s(&[1], &[3.0]).unwrap()]);
+
+        state.set_value(0, two.clone()).unwrap();
+        assert!(state.outputs[1].is_none());
+        assert!(state.outputs[3].is_none());
+        let result = state.run(vec![(0, two.clone()), (2, two.clone())], 3).unwrap();
+        assert_eq!(result, vec![Matrix::f32s(&[1], &[4.0]).unwrap()]);
+    }
+
+    #[test]
+    fn observers() {
+        use std::sync::Mutex;
+        struct Log(Arc<Mutex<Vec<String>>>);
+        impl Observer for Log {
+            fn before(&mut self, node: &Node, inputs: &[Input]) {
+                let log = format!("before {} {}", node.name, inputs.len());
+                self.0.lock().unwrap().push(log);
+            }
+            fn after(&mut self, node: &Node, outputs: &[Input]) {
+                let log = format!("after {} {:?}", node.name, outputs[0].shape());
+                self.0.lock().unwrap().push(log);
+            }
+        }
+        let model = for_path("tests/plus3.pb").unwrap();
+        let log = Arc::new(Mutex::new(vec![]));
+        let mut state = model.state();
+        state.add_observer(Box::new(Log(log.clone())));
+        let input = Matrix::f32s(&[2], &[1.0, 2.0]).unwrap();
+        state.run(vec![(0, input)], 2).unwrap();
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec!["before three 0", "after three [1]", "before output 2", "after output [2]"]
+        );
+        assert_eq!(state.clear_observers().len(), 1);
+    }
+
+    #[test]
+    fn run_multiple() {
+        let model = for_path("tests/plus3.pb").unwrap();
+        let input = Matrix::f32s(&[2], &[1.0, 2.0]).unwrap();
+        let outputs = model
+            .run_multiple_with_names(
+                vec![("input", input.clone())],
+                &["output", "three", "input"],
+            )
+            .unwrap();
+        assert_eq!(outputs.len(), 3);
+        assert_eq!(outputs[0][0], Matrix::f32s(&[2], &[4.0, 5.0]).unwrap());
+        assert_eq!(outputs[1][0], Matrix::f32s(&[1], &[3.0]).unwrap());
+        assert_eq!(outputs[2][0], input);
+    }
+
+    #[test]
+    fn run_multiple_same_output_twice() {
+        let model = for_path("tests/plus3.pb").unwrap();
+        let input = Matrix::f32s(&[1], &[1.0]).unwrap();
+        let outputs = model
+            .run_multiple_with_names(vec![("input", input)], &["output", "output"])
+            .unwrap();
+        assert_eq!(outputs[0], outputs[1]);
     }
 }
@@ -1,15 +1,25 @@
 //! `Matrix` is the equivalent of Tensorflow Tensor.
 
 use std::fmt::Debug;
+use half::f16;
 use ndarray::prelude::*;
 use tfpb::types::DataType;
+use errors::ErrorKind;
+
+/// Type of the elements of a matrix, numeric or not.
+pub trait Element: Clone + PartialEq + Send + Sync + Debug + 'static {
+    fn name() -> &'static str;
+    fn datatype() -> DataType;
+    fn mat_into_array(m: Matrix) -> ::Result<ArrayD<Self>>;
+    fn mat_to_view(m: &Matrix) -> ::Result<ArrayViewD<Self>>;
+    fn array_into_mat(m: ArrayD<Self>) -> Matrix;
+}
+
+/// Numeric element types.
 pub trait Datum
-    : Copy
-    + Clone
-    + Send
-    + Sync
-    + Debug
-    + 'static
+    : Element
+    + Copy
+    + PartialOrd
     + ::num_traits::Zero
     + ::num_traits::One
     + ::ndarray::LinalgScalar
@@ -18,20 +28,35 @@ pub trait Datum
     + ::std::ops::DivAssign
     + ::std::ops::SubAssign
     + ::std::ops::RemAssign {
-    fn name() -> &'static str;
-    fn mat_into_array(m: Matrix) -> ::Result<ArrayD<Self>>;
-    fn mat_to_view(m: &Matrix) -> ::Result<ArrayViewD<Self>>;
-    fn array_into_mat(m: ArrayD<Self>) -> Matrix;
+    /// `self / other`, or `None` for an integer division by zero or
+    /// overflowing.
+    fn checked_div(self, other: Self) -> Option<Self> {
+        Some(self / other)
+    }
+
+    /// `self % other`, or `None` for an integer division by zero or
+    /// overflowing.
+    fn checked_rem(self, other: Self) -> Option<Self> {
+        let mut rem = self;
+        rem %= other;
+        Some(rem)
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Matrix {
+    F16(ArrayD<f16>),
     F32(ArrayD<f32>),
     F64(ArrayD<f64>),
+    I64(ArrayD<i64>),
     I32(ArrayD<i32>),
+    I16(ArrayD<i16>),
     I8(ArrayD<i8>),
+    U16(ArrayD<u16>),
     U8(ArrayD<u8>),
-    String(ArrayD<i8>),
+    Bool(ArrayD<bool>),
+    /// One byte buffer per element, as strings are not necessarily UTF-8.
+    String(ArrayD<Vec<u8>>),
 }
 
 impl Matrix {
@@ -39,43 +64,114 @@ impl Matrix {
         use tfpb::types::DataType::*;
         let dtype = t.get_dtype();
         let shape = t.get_tensor_shape();
+        let malformed = |reason: String| {
+            let attr = "tensor_shape".to_string();
+            ErrorKind::MalformedAttribute(String::new(), String::new(), attr, reason)
+        };
         let dims = shape
             .get_dim()
             .iter()
-            .map(|d| d.size as usize)
-            .collect::<Vec<_>>();
+            .map(|d| {
+                if d.size < 0 {
+                    Err(malformed(format!("negative dimension {}", d.size)))?
+                }
+                Ok(d.size as usize)
+            })
+            .collect::<::Result<Vec<_>>>()?;
+        // no tensor of any datatype could be that large
+        if element_count::<u64>(&dims).is_err() {
+            Err(malformed(format!("too many elements in shape {:?}", dims)))?
+        }
         let rank = dims.len();
         let content = t.get_tensor_content();
         let mat: Matrix = if content.len() != 0 {
             match dtype {
-                DT_FLOAT => Self::from_content::<f32, u8>(dims, content)?.into(),
-                DT_INT32 => Self::from_content::<i32, u8>(dims, content)?.into(),
-                _ => unimplemented!(),
+                DT_HALF => Self::from_content::<u16>(dims, content)?
+                    .mapv(f16::from_bits)
+                    .into(),
+                DT_FLOAT => Self::from_content::<f32>(dims, content)?.into(),
+                DT_DOUBLE => Self::from_content::<f64>(dims, content)?.into(),
+                DT_INT64 => Self::from_content::<i64>(dims, content)?.into(),
+                DT_INT32 => Self::from_content::<i32>(dims, content)?.into(),
+                DT_INT16 => Self::from_content::<i16>(dims, content)?.into(),
+                DT_INT8 => Self::from_content::<i8>(dims, content)?.into(),
+                DT_UINT16 => Self::from_content::<u16>(dims, content)?.into(),
+                DT_UINT8 => Self::from_content::<u8>(dims, content)?.into(),
+                DT_BOOL => Self::from_content::<u8>(dims, content)?.mapv(|b| b != 0).into(),
+                _ => Err(ErrorKind::UnsupportedDatatype(
+                    String::new(),
+                    "TensorProto decoding".into(),
+                    dtype,
+                ))?,
             }
         } else {
             match dtype {
-                DT_INT32 => Self::from_content::<i32, i32>(dims, t.get_int_val())?.into(),
-                DT_FLOAT => Self::from_content::<f32, f32>(dims, t.get_float_val())?.into(),
-                _ => unimplemented!(),
+                DT_HALF => {
+                    Self::from_values(dims, t.get_half_val(), |&x| f16::from_bits(x as u16))?.into()
+                }
+                DT_FLOAT => Self::from_values(dims, t.get_float_val(), |&x| x)?.into(),
+                DT_DOUBLE => Self::from_values(dims, t.get_double_val(), |&x| x)?.into(),
+                DT_INT64 => Self::from_values(dims, t.get_int64_val(), |&x| x)?.into(),
+                DT_INT32 => Self::from_values(dims, t.get_int_val(), |&x| x)?.into(),
+                DT_INT16 => Self::from_values(dims, t.get_int_val(), |&x| x as i16)?.into(),
+                DT_INT8 => Self::from_values(dims, t.get_int_val(), |&x| x as i8)?.into(),
+                DT_UINT16 => Self::from_values(dims, t.get_int_val(), |&x| x as u16)?.into(),
+                DT_UINT8 => Self::from_values(dims, t.get_int_val(), |&x| x as u8)?.into(),
+                DT_BOOL => Self::from_values(dims, t.get_bool_val(), |&x| x)?.into(),
+                DT_STRING => Self::from_values(dims, t.get_string_val(), |x| x.clone())?.into(),
+                _ => Err(ErrorKind::UnsupportedDatatype(
+                    String::new(),
+                    "TensorProto decoding".into(),
+                    dtype,
+                ))?,
             }
         };
-        assert_eq!(rank, mat.shape().len());
+        if rank != mat.shape().len() {
+            Err(format!("Decoded a tensor of rank {}, expected {}", mat.shape().len(), rank))?
+        }
         Ok(mat)
     }
 
-    pub fn from_content<T: Copy, V: Copy>(dims: Vec<usize>, content: &[V]) -> ::Result<ArrayD<T>> {
-        let value: &[T] = unsafe {
-            ::std::slice::from_raw_parts(
-                content.as_ptr() as _,
-                content.len() * ::std::mem::size_of::<V>() / ::std::mem::size_of::<T>(),
-            )
-        };
-        Ok(Array1::from_iter(value.iter().cloned())
-            .into_shape(dims)?
-            .into_dyn())
+    /// Decode the values of a repeated field of a TensorProto. As in
+    /// TensorFlow, when there are fewer values than elements the last value
+    /// fills the rest of the tensor, and an empty field stands for zeros.
+    fn from_values<V, T, F>(dims: Vec<usize>, values: &[V], f: F) -> ::Result<ArrayD<T>>
+    where
+        T: Clone + Default,
+        F: Fn(&V) -> T,
+    {
+        let len = element_count::<T>(&dims)?;
+        if values.len() > len {
+            Err(format!("Found {} values for a tensor of shape {:?}", values.len(), dims))?
+        }
+        let mut data: Vec<T> = values.iter().map(f).collect();
+        let last = data.last().cloned().unwrap_or_default();
+        data.try_reserve_exact(len - data.len())
+            .map_err(|_| format!("Can not allocate a tensor of shape {:?}", dims))?;
+        data.resize(len, last);
+        Ok(ArrayD::from_shape_vec(dims, data)?)
     }
 
-    pub fn to_pb(&self) -> ::Result<::tfpb::tensor::TensorProto> {
+    /// Decode `tensor_content`, which holds the values as little-endian
+    /// bytes with no alignment guarantee.
+    pub fn from_content<T: FromLeBytes>(dims: Vec<usize>, content: &[u8]) -> ::Result<ArrayD<T>> {
+        let size = ::std::mem::size_of::<T>();
+        let bytes = element_count::<T>(&dims)?
+            .checked_mul(size)
+            .ok_or_else(|| format!("Shape {:?} holds too many elements", dims))?;
+        if content.len() != bytes {
+            Err(format!(
+                "Found {} bytes of content for a tensor of shape {:?}, expected {}",
+                content.len(),
+                dims,
+                bytes
+            ))?
+        }
+        let data = content.chunks(size).map(T::from_le_bytes).collect();
+        Ok(ArrayD::from_shape_vec(dims, data)?)
+    }
+
+    fn empty_pb(&self) -> ::tfpb::tensor::TensorProto {
         let mut shape = ::tfpb::tensor_shape::TensorShapeProto::new();
         let dims = self.shape()
             .iter()
@@ -88,59 +184,153 @@ impl Matrix {
         shape.set_dim(::protobuf::RepeatedField::from_vec(dims));
         let mut tensor = ::tfpb::tensor::TensorProto::new();
         tensor.set_tensor_shape(shape);
+        tensor.set_dtype(self.datatype());
+        tensor
+    }
+
+    /// Encode to a TensorProto, with the values in the repeated field
+    /// matching the datatype.
+    pub fn to_pb(&self) -> ::Result<::tfpb::tensor::TensorProto> {
+        let mut tensor = self.empty_pb();
         match self {
-            &Matrix::F32(ref it) => {
-                tensor.set_dtype(DataType::DT_FLOAT);
-                tensor.set_float_val(it.iter().cloned().collect());
+            &Matrix::F16(ref it) => {
+                tensor.set_half_val(it.iter().map(|x| x.to_bits() as i32).collect())
             }
-            _ => unimplemented!(),
+            &Matrix::F32(ref it) => tensor.set_float_val(it.iter().cloned().collect()),
+            &Matrix::F64(ref it) => tensor.set_double_val(it.iter().cloned().collect()),
+            &Matrix::I64(ref it) => tensor.set_int64_val(it.iter().cloned().collect()),
+            &Matrix::I32(ref it) => tensor.set_int_val(it.iter().cloned().collect()),
+            &Matrix::I16(ref it) => tensor.set_int_val(it.iter().map(|&x| x as i32).collect()),
+            &Matrix::U16(ref it) => tensor.set_int_val(it.iter().map(|&x| x as i32).collect()),
+            &Matrix::I8(ref it) => tensor.set_int_val(it.iter().map(|&x| x as i32).collect()),
+            &Matrix::U8(ref it) => tensor.set_int_val(it.iter().map(|&x| x as i32).collect()),
+            &Matrix::Bool(ref it) => tensor.set_bool_val(it.iter().cloned().collect()),
+            &Matrix::String(ref it) => tensor.set_string_val(it.iter().cloned().collect()),
         }
         Ok(tensor)
     }
 
+    /// Encode to a TensorProto, with the values as little-endian bytes in
+    /// `tensor_content`, which is more compact for large tensors. Strings
+    /// have no such encoding and are stored in `string_val`.
+    pub fn to_pb_compact(&self) -> ::Result<::tfpb::tensor::TensorProto> {
+        fn bytes<T: Copy, B: AsRef<[u8]>>(array: &ArrayD<T>, f: fn(T) -> B) -> Vec<u8> {
+            let mut content = Vec::with_capacity(array.len() * ::std::mem::size_of::<T>());
+            for &x in array.iter() {
+                content.extend_from_slice(f(x).as_ref());
+            }
+            content
+        }
+        let content = match self {
+            &Matrix::F16(ref it) => bytes(it, |x| x.to_bits().to_le_bytes()),
+            &Matrix::F32(ref it) => bytes(it, f32::to_le_bytes),
+            &Matrix::F64(ref it) => bytes(it, f64::to_le_bytes),
+            &Matrix::I64(ref it) => bytes(it, i64::to_le_bytes),
+            &Matrix::I32(ref it) => bytes(it, i32::to_le_bytes),
+            &Matrix::I16(ref it) => bytes(it, i16::to_le_bytes),
+            &Matrix::U16(ref it) => bytes(it, u16::to_le_bytes),
+            &Matrix::I8(ref it) => bytes(it, i8::to_le_bytes),
+            &Matrix::U8(ref it) => bytes(it, u8::to_le_bytes),
+            &Matrix::Bool(ref it) => bytes(it, |x| [x as u8]),
+            &Matrix::String(_) => return self.to_pb(),
+        };
+        let mut tensor = self.empty_pb();
+        tensor.set_tensor_content(content);
+        Ok(tensor)
+    }
+
     pub fn shape(&self) -> &[usize] {
         match self {
+            &Matrix::I64(ref it) => it.shape(),
             &Matrix::I32(ref it) => it.shape(),
+            &Matrix::I16(ref it) => it.shape(),
+            &Matrix::U16(ref it) => it.shape(),
+            &Matrix::F16(ref it) => it.shape(),
             &Matrix::F32(ref it) => it.shape(),
+            &Matrix::F64(ref it) => it.shape(),
             &Matrix::U8(ref it) => it.shape(),
-            _ => unimplemented!(),
+            &Matrix::I8(ref it) => it.shape(),
+            &Matrix::Bool(ref it) => it.shape(),
+            &Matrix::String(ref it) => it.shape(),
         }
     }
 
     pub fn datatype(&self) -> ::tfpb::types::DataType {
         use tfpb::types::DataType;
         match self {
+            &Matrix::I64(_) => DataType::DT_INT64,
             &Matrix::I32(_) => DataType::DT_INT32,
+            &Matrix::I16(_) => DataType::DT_INT16,
+            &Matrix::U16(_) => DataType::DT_UINT16,
+            &Matrix::F16(_) => DataType::DT_HALF,
             &Matrix::F32(_) => DataType::DT_FLOAT,
+            &Matrix::F64(_) => DataType::DT_DOUBLE,
             &Matrix::U8(_) => DataType::DT_UINT8,
-            _ => unimplemented!(),
+            &Matrix::I8(_) => DataType::DT_INT8,
+            &Matrix::Bool(_) => DataType::DT_BOOL,
+            &Matrix::String(_) => DataType::DT_STRING,
+        }
+    }
+
+    /// Values of an int32 or int64 matrix, the two types TensorFlow uses for
+    /// shapes, axes and indices.
+    pub fn as_indices(&self) -> ::Result<ArrayD<i64>> {
+        match self {
+            &Matrix::I32(ref it) => Ok(it.mapv(|x| x as i64)),
+            &Matrix::I64(ref it) => Ok(it.clone()),
+            m => Err(m.mismatch(DataType::DT_INT32))?,
         }
     }
 
+    /// Error for an op expecting a matrix of another datatype.
+    pub fn mismatch(&self, expected: DataType) -> ErrorKind {
+        ErrorKind::DatatypeMismatch(String::new(), String::new(), expected, self.datatype())
+    }
+
     pub fn partial_dump(&self, _single_line: bool) -> ::Result<String> {
         if self.shape().iter().product::<usize>() > 25 {
             Ok(format!("{:?} {:?}", self.datatype(), self.shape()))
         } else {
-            Ok(match self {
-                &Matrix::I32(ref a) => format!("{:?} {:?}", self.datatype(), a).replace("\n", " "),
-                &Matrix::F32(ref a) => format!("{:?} {:?}", self.datatype(), a).replace("\n", " "),
-                &Matrix::U8(ref a) => format!("{:?} {:?}", self.datatype(), a).replace("\n", " "),
-                _ => unimplemented!(),
-            })
+            let content = match self {
+                &Matrix::I64(ref a) => format!("{:?}", a),
+                &Matrix::I32(ref a) => format!("{:?}", a),
+                &Matrix::I16(ref a) => format!("{:?}", a),
+                &Matrix::U16(ref a) => format!("{:?}", a),
+                &Matrix::F16(ref a) => format!("{:?}", a),
+                &Matrix::F32(ref a) => format!("{:?}", a),
+                &Matrix::F64(ref a) => format!("{:?}", a),
+                &Matrix::I8(ref a) => format!("{:?}", a),
+                &Matrix::U8(ref a) => format!("{:?}", a),
+                &Matrix::Bool(ref a) => format!("{:?}", a),
+                &Matrix::String(ref a) => {
+                    format!("{:?}", a.map(|s| String::from_utf8_lossy(s).into_owned()))
+                }
+            };
+            Ok(format!("{:?} {}", self.datatype(), content).replace("\n", " "))
         }
     }
 
-    fn to_f32(&self) -> Matrix {
+    fn to_f32(&self) -> Option<ArrayD<f32>> {
         match self {
-            &Matrix::I32(ref data) => Matrix::F32(data.map(|&a| a as f32)),
-            &Matrix::F32(_) => self.clone(),
-            _ => unimplemented!(),
+            &Matrix::I64(ref data) => Some(data.map(|&a| a as f32)),
+            &Matrix::I32(ref data) => Some(data.map(|&a| a as f32)),
+            &Matrix::I16(ref data) => Some(data.map(|&a| a as f32)),
+            &Matrix::U16(ref data) => Some(data.map(|&a| a as f32)),
+            &Matrix::F16(ref data) => Some(data.map(|&a| a.to_f32())),
+            &Matrix::I8(ref data) => Some(data.map(|&a| a as f32)),
+            &Matrix::U8(ref data) => Some(data.map(|&a| a as f32)),
+            &Matrix::Bool(ref data) => Some(data.map(|&a| a as u8 as f32)),
+            &Matrix::F64(ref data) => Some(data.map(|&a| a as f32)),
+            &Matrix::F32(ref data) => Some(data.clone()),
+            &Matrix::String(_) => None,
         }
     }
 
     pub fn close_enough(&self, other: &Self) -> bool {
-        let ma = self.to_f32().take_f32s().unwrap();
-        let mb = other.to_f32().take_f32s().unwrap();
+        let (ma, mb) = match (self.to_f32(), other.to_f32()) {
+            (Some(ma), Some(mb)) => (ma, mb),
+            _ => return self == other,
+        };
         let avg = ma.iter().map(|&a| a.abs()).sum::<f32>() / ma.len() as f32;
         let dev = (ma.iter().map(|&a| (a - avg).powi(2)).sum::<f32>() / ma.len() as f32).sqrt();
         ma.shape() == mb.shape()
@@ -171,7 +361,7 @@ where
 }
 
 macro_rules! matrix {
-    ($t:ident,$v:ident,$as:ident,$take:ident,$make:ident) => {
+    ($t:ty,$v:ident,$dt:ident,$as:ident,$take:ident,$make:ident) => {
         impl<D: ::ndarray::Dimension> From<Array<$t,D>> for Matrix {
             fn from(it: Array<$t,D>) -> Matrix {
                 Matrix::$v(it.into_dyn())
@@ -210,28 +400,198 @@ macro_rules! matrix {
             }
         }
 
-        impl Datum for $t {
+        impl Element for $t {
             fn name() -> &'static str {
                 stringify!($t)
             }
+
+            fn datatype() -> DataType {
+                DataType::$dt
+            }
+
             fn mat_into_array(m: Matrix) -> ::Result<ArrayD<Self>> {
-                m.$take().ok_or("unmatched data type".into())
+                match m {
+                    Matrix::$v(it) => Ok(it),
+                    m => Err(m.mismatch(DataType::$dt).into()),
+                }
             }
 
             fn mat_to_view(m: &Matrix) -> ::Result<ArrayViewD<Self>> {
-                m.$as().map(|m| m.view()).ok_or("unmatched data type".into())
+                m.$as().map(|m| m.view()).ok_or_else(|| m.mismatch(DataType::$dt).into())
             }
 
             fn array_into_mat(m: ArrayD<Self>) -> Matrix {
                 Matrix::from(m)
             }
-
         }
     }
 }
 
-matrix!(f64, F64, as_f64s, take_f64s, f64s);
-matrix!(f32, F32, as_f32s, take_f32s, f32s);
-matrix!(i32, I32, as_i32s, take_i32s, i32s);
-matrix!(u8, U8, as_u8s, take_u8s, u8s);
-matrix!(i8, I8, as_i8s, take_i8s, i8s);
+matrix!(f64, F64, DT_DOUBLE, as_f64s, take_f64s, f64s);
+matrix!(f32, F32, DT_FLOAT, as_f32s, take_f32s, f32s);
+matrix!(f16, F16, DT_HALF, as_f16s, take_f16s, f16s);
+matrix!(i64, I64, DT_INT64, as_i64s, take_i64s, i64s);
+matrix!(i32, I32, DT_INT32, as_i32s, take_i32s, i32s);
+matrix!(i16, I16, DT_INT16, as_i16s, take_i16s, i16s);
+matrix!(i8, I8, DT_INT8, as_i8s, take_i8s, i8s);
+matrix!(u16, U16, DT_UINT16, as_u16s, take_u16s, u16s);
+matrix!(u8, U8, DT_UINT8, as_u8s, take_u8s, u8s);
+matrix!(bool, Bool, DT_BOOL, as_bools, take_bools, bools);
+matrix!(Vec<u8>, String, DT_STRING, as_strings, take_strings, strings);
+
+impl Datum for f64 {}
+impl Datum for f32 {}
+impl Datum for f16 {}
+
+macro_rules! integer_datum {
+    ($($t:ty),*) => {
+        $(
+            impl Datum for $t {
+                fn checked_div(self, other: $t) -> Option<$t> {
+                    <$t>::checked_div(self, other)
+                }
+
+                fn checked_rem(self, other: $t) -> Option<$t> {
+                    <$t>::checked_rem(self, other)
+                }
+            }
+        )*
+    };
+}
+
+integer_datum!(i64, i32, i16, i8, u16, u8);
+
+/// Number of elements of a tensor of shape `dims`, checking that they can be
+/// held in memory.
+fn element_count<T>(dims: &[usize]) -> ::Result<usize> {
+    let len = dims.iter().try_fold(1usize, |len, &d| len.checked_mul(d));
+    let max = isize::max_value() as usize / ::std::mem::size_of::<T>().max(1);
+    match len {
+        Some(len) if len <= max => Ok(len),
+        _ => Err(format!("Shape {:?} holds too many elements", dims))?,
+    }
+}
+
+/// Values that can be read from their little-endian bytes.
+pub trait FromLeBytes: Sized {
+    fn from_le_bytes(bytes: &[u8]) -> Self;
+}
+
+macro_rules! from_le_bytes {
+    ($($t:ty),*) => {
+        $(
+            impl FromLeBytes for $t {
+                fn from_le_bytes(bytes: &[u8]) -> $t {
+                    let mut buf = [0u8; ::std::mem::size_of::<$t>()];
+                    buf.copy_from_slice(bytes);
+                    <$t>::from_le_bytes(buf)
+                }
+            }
+        )*
+    };
+}
+
+from_le_bytes!(f64, f32, i64, i32, i16, i8, u16, u8);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn all_dtypes() -> Vec<Matrix> {
+        vec![
+            Matrix::f16s(&[2], &[f16::from_f32(0.5), f16::from_f32(-2.0)]).unwrap(),
+            Matrix::f32s(&[2, 2], &[1.0, -2.5, 3.0, 0.0]).unwrap(),
+            Matrix::f64s(&[3], &[1e-300, -2.0, 3.5]).unwrap(),
+            Matrix::i64s(&[2], &[i64::min_value(), 1 << 40]).unwrap(),
+            Matrix::i32s(&[1, 2], &[-7, i32::max_value()]).unwrap(),
+            Matrix::i16s(&[2], &[-300, 300]).unwrap(),
+            Matrix::i8s(&[2], &[-128, 127]).unwrap(),
+            Matrix::u16s(&[2], &[0, 65535]).unwrap(),
+            Matrix::u8s(&[2], &[0, 255]).unwrap(),
+            Matrix::bools(&[3], &[true, false, true]).unwrap(),
+            Matrix::strings(&[2], &[b"ab".to_vec(), vec![]]).unwrap(),
+            Matrix::f32s(&[], &[42.0]).unwrap(),
+        ]
+    }
+
+    #[test]
+    fn tensor_proto_round_trip() {
+        for m in all_dtypes() {
+            assert_eq!(Matrix::from_pb(&m.to_pb().unwrap()).unwrap(), m);
+            assert_eq!(Matrix::from_pb(&m.to_pb_compact().unwrap()).unwrap(), m);
+        }
+        let compact = Matrix::i32s(&[2], &[1, -1]).unwrap().to_pb_compact().unwrap();
+        assert_eq!(compact.get_tensor_content(), &[1, 0, 0, 0, 255, 255, 255, 255]);
+        assert!(compact.get_int_val().is_empty());
+    }
+
+    #[test]
+    fn tensor_proto_fill() {
+        let fill = ::tfpb::tensor_f32(vec![2, 2], vec![1.5]);
+        assert_eq!(Matrix::from_pb(&fill).unwrap(), Matrix::f32s(&[2, 2], &[1.5; 4]).unwrap());
+
+        let mut last = ::tfpb::tensor_f32(vec![4], vec![]);
+        last.set_dtype(::tfpb::types::DataType::DT_INT64);
+        last.set_int64_val(vec![1, 2]);
+        assert_eq!(Matrix::from_pb(&last).unwrap(), Matrix::i64s(&[4], &[1, 2, 2, 2]).unwrap());
+
+        let zeros = ::tfpb::tensor_f32(vec![3], vec![]);
+        assert_eq!(Matrix::from_pb(&zeros).unwrap(), Matrix::f32s(&[3], &[0.0; 3]).unwrap());
+
+        let long = ::tfpb::tensor_f32(vec![2], vec![1.0, 2.0, 3.0]);
+        assert!(Matrix::from_pb(&long).is_err());
+    }
+
+    #[test]
+    fn tensor_content_misaligned() {
+        let mut buffer = vec![0u8];
+        for x in &[1.5f64, -2.0, 1e300] {
+            buffer.extend_from_slice(&x.to_le_bytes());
+        }
+        let found = Matrix::from_content::<f64>(vec![3], &buffer[1..]).unwrap();
+        assert_eq!(found, arr1(&[1.5, -2.0, 1e300]).into_dyn());
+
+        let buffer = [0xff, 0x01, 0x00, 0x00, 0x00, 0xfe, 0xff, 0xff, 0xff];
+        let found = Matrix::from_content::<i32>(vec![2], &buffer[1..]).unwrap();
+        assert_eq!(found, arr1(&[1, -2]).into_dyn());
+        let found = Matrix::from_content::<i16>(vec![2, 2], &buffer[1..]).unwrap();
+        assert_eq!(found, arr2(&[[1, 0], [-2, -1]]).into_dyn());
+    }
+
+    #[test]
+    fn tensor_content_length_mismatch() {
+        let buffer = [0u8; 9];
+        assert!(Matrix::from_content::<i32>(vec![2], &buffer).is_err());
+        assert!(Matrix::from_content::<i32>(vec![3], &buffer).is_err());
+        assert!(Matrix::from_content::<u8>(vec![3, 3], &buffer).is_ok());
+        assert!(Matrix::from_content::<f64>(vec![1 << 61, 4], &buffer).is_err());
+        assert!(Matrix::from_content::<u8>(vec![usize::max_value(), 2], &buffer).is_err());
+
+        let mut pb = Matrix::f32s(&[2], &[1.0, 2.0]).unwrap().to_pb_compact().unwrap();
+        pb.mut_tensor_content().pop();
+        assert!(Matrix::from_pb(&pb).is_err());
+    }
+
+    #[test]
+    fn malformed_tensor_shapes() {
+        let malformed = |dims: Vec<i64>| {
+            let mut pb = ::tfpb::tensor_f32(vec![], vec![1.0]);
+            let dims = dims.into_iter()
+                .map(|size| {
+                    let mut dim = ::tfpb::tensor_shape::TensorShapeProto_Dim::new();
+                    dim.size = size;
+                    dim
+                })
+                .collect();
+            pb.mut_tensor_shape().set_dim(::protobuf::RepeatedField::from_vec(dims));
+            match Matrix::from_pb(&pb).err().map(|e| e.0) {
+                Some(ErrorKind::MalformedAttribute(_, _, ref attr, _)) => attr == "tensor_shape",
+                _ => false,
+            }
+        };
+        assert!(malformed(vec![-1]));
+        assert!(malformed(vec![2, -3]));
+        assert!(malformed(vec![1 << 40, 1 << 40]));
+        assert!(malformed(vec![1 << 62]));
+    }
+}
@@ -0,0 +1,81 @@
+use std::marker::PhantomData;
+
+use ndarray::prelude::*;
+
+use analyser::TensorFact;
+use Result;
+use super::{Input, Op};
+use matrix::Element;
+
+/// Slices of `params` along its first axis, for each of `indices`.
+#[derive(Debug, new)]
+pub struct Gather<T: Element> {
+    _phantom: PhantomData<T>,
+}
+
+pub fn gather(pb: &::tfpb::node_def::NodeDef) -> Result<Box<Op>> {
+    let dtype = pb.get_attr_datatype("Tparams")?;
+    Ok(boxed_new_element!(Gather(dtype)()))
+}
+
+impl<T> Op for Gather<T>
+where
+    T: Element,
+{
+    fn eval(&self, mut inputs: Vec<Input>) -> Result<Vec<Input>> {
+        let (params, indices) = args_2!(inputs);
+        let params = T::mat_to_view(&params)?;
+        let indices = indices.as_indices()?;
+        if params.ndim() == 0 {
+            Err("Gather: params must be at least 1D")?
+        }
+        let mut shape = indices.shape().to_vec();
+        shape.extend(&params.shape()[1..]);
+        let mut data = Vec::with_capacity(shape.iter().product());
+        for &i in indices.iter() {
+            if i < 0 || i as usize >= params.shape()[0] {
+                Err(format!("Gather: index {} out of range 0..{}", i, params.shape()[0]))?
+            }
+            data.extend(params.subview(Axis(0), i as usize).iter().cloned());
+        }
+        Ok(vec![T::array_into_mat(ArrayD::from_shape_vec(shape, data)?).into()])
+    }
+
+    fn infer(&self, mut inputs: Vec<&TensorFact>) -> Result<Vec<TensorFact>> {
+        let (params, indices) = args_2!(inputs);
+        let shape = match (&params.shape, &indices.shape) {
+            (&Some(ref params), &Some(ref indices)) if !params.is_empty() => {
+                Some(indices.iter().chain(&params[1..]).cloned().collect())
+            }
+            _ => None,
+        };
+        Ok(vec![TensorFact {
+            datatype: params.datatype,
+            shape,
+            value: None,
+        }])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use Matrix;
+    use super::*;
+
+    #[test]
+    fn gather() {
+        let params = Matrix::strings(
+            &[3, 2],
+            &[b"a".to_vec(), b"b".to_vec(), b"c".to_vec(), b"d".to_vec(), b"e".to_vec(), vec![]],
+        ).unwrap();
+        let indices = Matrix::i32s(&[2], &[2, 0]).unwrap();
+        let found = Gather::<Vec<u8>>::new()
+            .eval(vec![params.clone().into(), indices.into()])
+            .unwrap();
+        let expected = [b"e".to_vec(), vec![], b"a".to_vec(), b"b".to_vec()];
+        assert_eq!(*found[0], Matrix::strings(&[2, 2], &expected).unwrap());
+
+        let indices = Matrix::i32s(&[1], &[3]).unwrap();
+        assert!(Gather::<Vec<u8>>::new().eval(vec![params.into(), indices.into()]).is_err());
+    }
+}
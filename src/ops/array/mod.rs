@@ -1,15 +1,23 @@
 use ndarray::prelude::*;
 
+mod gather;
 mod pack;
 mod strided_slice;
 
+use analyser::TensorFact;
+use errors::ErrorKind;
+use matrix::Element;
+use tfpb::types::DataType;
+use tfpb::types::DataType::*;
 use {Matrix, Result};
 use super::{Input, Op, OpRegister};
 
 pub fn register_all_ops(reg: &mut OpRegister) {
     reg.insert("ConcatV2", ConcatV2::build);
     reg.insert("ExpandDims", ExpandDims::build);
+    reg.insert("Gather", gather::gather);
     reg.insert("Identity", Identity::build);
+    reg.insert("IdentityN", Identity::build);
     reg.insert("Pack", pack::pack);
     reg.insert("Placeholder", Placeholder::build);
     reg.insert("Reshape", Reshape::build);
@@ -18,6 +26,29 @@ pub fn register_all_ops(reg: &mut OpRegister) {
     reg.insert("StridedSlice", strided_slice::build);
 }
 
+/// `ndarray::stack`, for elements that are not `Copy`.
+fn stack<T: Clone>(axis: Axis, arrays: &[ArrayViewD<T>]) -> Result<ArrayD<T>> {
+    let mut shape = arrays.first().ok_or("Can not stack zero arrays")?.shape().to_vec();
+    let compatible = |a: &ArrayViewD<T>| {
+        a.ndim() == shape.len() && (0..a.ndim()).all(|d| d == axis.0 || a.shape()[d] == shape[d])
+    };
+    if axis.0 >= shape.len() || !arrays.iter().all(compatible) {
+        let shapes: Vec<&[usize]> = arrays.iter().map(|a| a.shape()).collect();
+        Err(format!("Can not stack arrays of shapes {:?} along axis {}", shapes, axis.0))?
+    }
+    shape[axis.0] = arrays.iter().map(|a| a.shape()[axis.0]).sum();
+    let outer: usize = shape[..axis.0].iter().product();
+    let contents: Vec<Vec<T>> = arrays.iter().map(|a| a.iter().cloned().collect()).collect();
+    let mut data = Vec::with_capacity(shape.iter().product());
+    for o in 0..outer {
+        for content in &contents {
+            let block = content.len() / outer;
+            data.extend_from_slice(&content[o * block..(o + 1) * block]);
+        }
+    }
+    Ok(ArrayD::from_shape_vec(shape, data)?)
+}
+
 #[derive(Debug)]
 pub struct ConcatV2 {
     n: usize,
@@ -31,22 +62,71 @@ impl ConcatV2 {
     }
 }
 
+impl ConcatV2 {
+    fn concat<T: Element>(axis: usize, inputs: &[Input]) -> Result<Matrix> {
+        let mats: Vec<ArrayViewD<T>> = inputs
+            .iter()
+            .map(|mat| T::mat_to_view(mat))
+            .collect::<Result<_>>()?;
+        Ok(T::array_into_mat(stack(Axis(axis), &mats)?))
+    }
+}
+
 impl Op for ConcatV2 {
     fn eval(&self, inputs: Vec<Input>) -> Result<Vec<Input>> {
-        let axis: i32 = inputs[self.n]
-            .as_i32s()
-            .ok_or("Expected a i32 matrix")?
+        if inputs.len() != self.n + 1 || self.n == 0 {
+            Err(format!("Expected {} args, got {}", self.n + 1, inputs.len()))?
+        }
+        let axis = *inputs[self.n]
+            .as_indices()?
             .iter()
             .next()
-            .unwrap()
-            .clone();
-        let mats: Vec<_> = inputs[0..self.n]
+            .ok_or("Expected a scalar axis")?;
+        let rank = inputs[0].shape().len() as i64;
+        if axis < -rank || axis >= rank {
+            Err(format!("Invalid axis {} for tensors of rank {}", axis, rank))?
+        }
+        let axis = if axis < 0 { axis + rank } else { axis } as usize;
+        let inputs = &inputs[0..self.n];
+        let result = dispatch_element!(ConcatV2::concat(inputs[0].datatype())(axis, inputs))?;
+        Ok(vec![result.into()])
+    }
+
+    fn infer(&self, inputs: Vec<&TensorFact>) -> Result<Vec<TensorFact>> {
+        if inputs.len() != self.n + 1 {
+            Err(format!("Expected {} args", self.n + 1))?
+        }
+        let datatype = inputs[..self.n].iter().filter_map(|i| i.datatype).next();
+        let shapes: Vec<&Vec<Option<usize>>> = match inputs[..self.n]
             .iter()
-            .map(|mat| mat.as_f32s().unwrap().view())
+            .map(|i| i.shape.as_ref())
+            .collect()
+        {
+            Some(shapes) => shapes,
+            None => return Ok(vec![TensorFact { datatype, ..TensorFact::new() }]),
+        };
+        if shapes.iter().any(|s| s.len() != shapes[0].len()) {
+            Err(format!("Can not concatenate tensors of shapes {:?}", shapes))?
+        }
+        let axis = inputs[self.n]
+            .value
+            .as_ref()
+            .and_then(|v| v.as_indices().ok())
+            .and_then(|v| v.iter().next().cloned());
+        let shape = (0..shapes[0].len())
+            .map(|d| {
+                if axis.map(|a| a as usize == d).unwrap_or(true) {
+                    if axis.is_some() {
+                        shapes.iter().map(|s| s[d]).sum()
+                    } else {
+                        None
+                    }
+                } else {
+                    shapes.iter().filter_map(|s| s[d]).next()
+                }
+            })
             .collect();
-        let result = ::ndarray::stack(Axis(axis as usize), &*mats)?;
-        let result = Matrix::from(result);
-        Ok(vec![result.into()])
+        Ok(vec![TensorFact::shaped(datatype, shape)])
     }
 }
 
@@ -62,10 +142,8 @@ impl ExpandDims {
 impl Op for ExpandDims {
     fn eval(&self, mut inputs: Vec<Input>) -> Result<Vec<Input>> {
         let (data, dims) = args_2!(inputs);
-        let data = data.into_matrix()
-            .take_f32s()
-            .ok_or("Expected a f32 matrix")?;
-        let dims = dims.as_i32s().ok_or("Expected a i32 matrix")?;
+        let data = f32::mat_into_array(data.into_matrix())?;
+        let dims = dims.as_indices()?;
         let mut shape = data.shape().to_vec();
         for d in dims.iter() {
             if *d >= 0 {
@@ -76,6 +154,29 @@ impl Op for ExpandDims {
         }
         Ok(vec![Matrix::from(data.into_shape(shape)?).into()])
     }
+
+    fn infer(&self, mut inputs: Vec<&TensorFact>) -> Result<Vec<TensorFact>> {
+        let (data, dims) = args_2!(inputs);
+        let dims = match dims.value {
+            Some(ref dims) => dims.as_indices()?,
+            None => return Ok(vec![TensorFact { datatype: data.datatype, ..TensorFact::new() }]),
+        };
+        let shape = match data.shape {
+            Some(ref shape) => {
+                let mut shape = shape.clone();
+                for d in dims.iter() {
+                    if *d >= 0 {
+                        shape.insert(*d as usize, Some(1));
+                    } else {
+                        Err(format!("unimplemented ExpandDims with negative parameter"))?
+                    }
+                }
+                Some(shape)
+            }
+            None => None,
+        };
+        Ok(vec![TensorFact { datatype: data.datatype, shape, value: None }])
+    }
 }
 
 #[derive(Debug)]
@@ -91,20 +192,48 @@ impl Op for Identity {
     fn eval(&self, inputs: Vec<Input>) -> Result<Vec<Input>> {
         Ok(inputs)
     }
+
+    fn infer(&self, inputs: Vec<&TensorFact>) -> Result<Vec<TensorFact>> {
+        Ok(inputs.into_iter().cloned().collect())
+    }
 }
 
 #[derive(Debug)]
-pub struct Placeholder;
+pub struct Placeholder {
+    dtype: Option<DataType>,
+    shape: Option<Vec<Option<usize>>>,
+}
 
 impl Placeholder {
-    pub fn build(_pb: &::tfpb::node_def::NodeDef) -> Result<Box<Op>> {
-        Ok(Box::new(Placeholder))
+    pub fn build(pb: &::tfpb::node_def::NodeDef) -> Result<Box<Op>> {
+        let shape = match pb.get_attr().get("shape").map(|s| s.get_shape()) {
+            Some(shape) if !shape.get_unknown_rank() => Some(
+                shape
+                    .get_dim()
+                    .iter()
+                    .map(|d| if d.get_size() >= 0 { Some(d.get_size() as usize) } else { None })
+                    .collect(),
+            ),
+            _ => None,
+        };
+        Ok(Box::new(Placeholder {
+            dtype: pb.get_attr_opt_datatype("dtype")?,
+            shape,
+        }))
     }
 }
 
 impl Op for Placeholder {
     fn eval(&self, _inputs: Vec<Input>) -> Result<Vec<Input>> {
-        panic!("Placeholder should not get evaluated")
+        Err(ErrorKind::MissingFeed(String::new(), "Placeholder".into()))?
+    }
+
+    fn infer(&self, _inputs: Vec<&TensorFact>) -> Result<Vec<TensorFact>> {
+        Ok(vec![TensorFact {
+            datatype: self.dtype,
+            shape: self.shape.clone(),
+            value: None,
+        }])
     }
 }
 
@@ -115,49 +244,97 @@ impl Reshape {
     pub fn build(_pb: &::tfpb::node_def::NodeDef) -> Result<Box<Op>> {
         Ok(Box::new(Reshape {}))
     }
+
+    fn reshape<T: Element>(input: Matrix, dims: &[usize]) -> Result<Matrix> {
+        Ok(T::array_into_mat(T::mat_into_array(input)?.into_shape(dims)?))
+    }
 }
 
 impl Op for Reshape {
     fn eval(&self, mut inputs: Vec<Input>) -> Result<Vec<Input>> {
         let (input, dims) = args_2!(inputs);
-        let input = input
-            .into_matrix()
-            .take_f32s()
-            .ok_or("Expected a f32 matrix")?;
-        let mut dims: Vec<i32> = dims.as_i32s()
-            .ok_or("Expected a i32 matrix")?
-            .iter()
-            .cloned()
+        let input = input.into_matrix();
+        let len: usize = input.shape().iter().product();
+        let dims: Vec<usize> = resolve_dims(&dims.as_indices()?, Some(len))?
+            .into_iter()
+            .map(|d| d.unwrap())
             .collect();
-        if dims.contains(&-1) {
-            let prod: i32 = dims.iter().map(|a| *a).filter(|a| *a != -1i32).product();
-            for a in dims.iter_mut() {
-                if *a == -1 {
-                    *a = input.len() as i32 / prod;
-                }
-            }
-        }
-        let dims: Vec<usize> = dims.into_iter().map(|a| a as usize).collect();
-        Ok(vec![
-            Matrix::from(input.into_shape(&*dims)?.into_dyn()).into(),
-        ])
+        let output = dispatch_element!(Reshape::reshape(input.datatype())(input, &dims))?;
+        Ok(vec![output.into()])
+    }
+
+    fn infer(&self, mut inputs: Vec<&TensorFact>) -> Result<Vec<TensorFact>> {
+        let (input, dims) = args_2!(inputs);
+        let dims = match dims.value {
+            Some(ref dims) => dims.as_indices()?,
+            None => return Ok(vec![TensorFact { datatype: input.datatype, ..TensorFact::new() }]),
+        };
+        let len: Option<usize> = input.concrete_shape().map(|s| s.iter().product());
+        let shape = resolve_dims(&dims, len)?;
+        Ok(vec![TensorFact::shaped(input.datatype, shape)])
     }
 }
 
+/// Dimensions of a Reshape output holding `len` elements, replacing the
+/// `-1` dimension, if any, by the size it must have. This size is unknown
+/// if `len` is.
+fn resolve_dims(dims: &ArrayD<i64>, len: Option<usize>) -> Result<Vec<Option<usize>>> {
+    if dims.iter().any(|&d| d < -1) || dims.iter().filter(|&&d| d == -1).count() > 1 {
+        Err(format!("Reshape: invalid dimensions {}", dims))?
+    }
+    let prod: usize = dims.iter().filter(|&&d| d != -1).map(|&d| d as usize).product();
+    let missing = match len {
+        _ if !dims.iter().any(|&d| d == -1) => None,
+        _ if prod == 0 => Err("Reshape: can not infer the -1 dimension next to a 0 dimension")?,
+        Some(len) if len % prod != 0 => Err(format!(
+            "Reshape: can not reshape {} elements with dimensions {}",
+            len, dims
+        ))?,
+        len => len.map(|len| len / prod),
+    };
+    Ok(dims.iter()
+        .map(|&d| if d == -1 { missing } else { Some(d as usize) })
+        .collect())
+}
+
 #[derive(Debug)]
-pub struct Shape;
+pub struct Shape {
+    out_type: DataType,
+}
 
 impl Shape {
-    pub fn build(_pb: &::tfpb::node_def::NodeDef) -> Result<Box<Op>> {
-        Ok(Box::new(Shape))
+    pub fn build(pb: &::tfpb::node_def::NodeDef) -> Result<Box<Op>> {
+        Ok(Box::new(Shape {
+            out_type: pb.get_attr_opt_datatype("out_type")?.unwrap_or(DT_INT32),
+        }))
+    }
+
+    fn shape(&self, shape: &[usize]) -> Result<Matrix> {
+        match self.out_type {
+            DT_INT32 => Ok(Array1::from_iter(shape.iter().map(|&s| s as i32)).into()),
+            DT_INT64 => Ok(Array1::from_iter(shape.iter().map(|&s| s as i64)).into()),
+            dt => Err(ErrorKind::UnsupportedDatatype(String::new(), "Shape".into(), dt))?,
+        }
     }
 }
 
 impl Op for Shape {
-    fn eval(&self, inputs: Vec<Input>) -> Result<Vec<Input>> {
-        let data = inputs[0].as_f32s().ok_or("Expect input #0 to be f32")?;
-        let shape: Vec<i32> = data.shape().into_iter().map(|s| *s as i32).collect();
-        Ok(vec![Matrix::from(Array1::from_vec(shape)).into()])
+    fn eval(&self, mut inputs: Vec<Input>) -> Result<Vec<Input>> {
+        let input = args_1!(inputs);
+        Ok(vec![self.shape(input.shape())?.into()])
+    }
+
+    fn infer(&self, mut inputs: Vec<&TensorFact>) -> Result<Vec<TensorFact>> {
+        let input = args_1!(inputs);
+        let value = match input.concrete_shape() {
+            Some(shape) => Some(self.shape(&shape)?),
+            None => None,
+        };
+        Ok(vec![TensorFact {
+            datatype: Some(self.out_type),
+            shape: Some(vec![input.rank()]),
+            value,
+        }])
     }
 }
 
@@ -177,7 +354,7 @@ impl Squeeze {
 
 impl Op for Squeeze {
     fn eval(&self, inputs: Vec<Input>) -> Result<Vec<Input>> {
-        let data = inputs[0].as_f32s().ok_or("Expect input #0 to be f32")?;
+        let data = inputs[0].as_f32s().ok_or_else(|| inputs[0].mismatch(DT_FLOAT))?;
         let mut shape = data.shape().to_vec();
         for d in &self.dims {
             if *d >= 0 {
@@ -188,4 +365,76 @@ impl Op for Squeeze {
         }
         Ok(vec![Matrix::from(data.clone().into_shape(shape)?).into()])
     }
+
+    fn infer(&self, mut inputs: Vec<&TensorFact>) -> Result<Vec<TensorFact>> {
+        let input = args_1!(inputs);
+        let shape = match input.shape {
+            Some(ref shape) => {
+                let mut shape = shape.clone();
+                for d in &self.dims {
+                    if *d >= 0 && (*d as usize) < shape.len() {
+                        shape.remove(*d as usize);
+                    } else {
+                        Err(format!("unimplemented Squeeze of dim {} in {:?}", d, shape))?
+                    }
+                }
+                Some(shape)
+            }
+            None => None,
+        };
+        Ok(vec![TensorFact { datatype: input.datatype, shape, value: None }])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reshape(input: Matrix, dims: &[i32]) -> Result<Matrix> {
+        let dims = Matrix::i32s(&[dims.len()], dims)?;
+        let mut outputs = Reshape {}.eval(vec![input.into(), dims.into()])?;
+        Ok(outputs.remove(0).into_matrix())
+    }
+
+    #[test]
+    fn reshape_dims() {
+        let input = Matrix::f32s(&[2, 3], &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]).unwrap();
+        assert_eq!(reshape(input.clone(), &[3, -1]).unwrap().shape(), &[3, 2]);
+        assert!(reshape(input.clone(), &[4, -1]).is_err());
+        assert!(reshape(input.clone(), &[-1, -1]).is_err());
+        assert!(reshape(input, &[-2, -3]).is_err());
+
+        let empty = Matrix::f32s(&[0], &[]).unwrap();
+        assert_eq!(reshape(empty.clone(), &[0, 3]).unwrap().shape(), &[0, 3]);
+        assert!(reshape(empty, &[0, -1]).is_err());
+    }
+
+    #[test]
+    fn reshape_error_in_model() {
+        let graph = ::tfpb::graph()
+            .node(::tfpb::node().name("x").op("Placeholder"))
+            .node(::tfpb::node().name("dims").op("Const").attr(
+                "value",
+                Matrix::i32s(&[2], &[0, -1]).unwrap().to_pb().unwrap(),
+            ))
+            .node(::tfpb::node().name("reshape").op("Reshape").input("x").input("dims"));
+        let model = ::Model::new(graph).unwrap();
+        let x = Matrix::f32s(&[0], &[]).unwrap();
+        let err = model.run_with_names(vec![("x", x)], "reshape").err().unwrap();
+        match *err.kind() {
+            ErrorKind::EvalFailed(ref node, _) => assert_eq!(node, "reshape"),
+            ref e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn reshape_infer() {
+        let input = TensorFact::shaped(Some(DT_FLOAT), vec![Some(0)]);
+        let dims = TensorFact::from_value(Matrix::i32s(&[2], &[0, -1]).unwrap());
+        assert!(Reshape {}.infer(vec![&input, &dims]).is_err());
+        let input = TensorFact::shaped(Some(DT_FLOAT), vec![None, Some(2)]);
+        let dims = TensorFact::from_value(Matrix::i32s(&[2], &[2, -1]).unwrap());
+        let found = Reshape {}.infer(vec![&input, &dims]).unwrap();
+        assert_eq!(found[0].shape, Some(vec![Some(2), None]));
+    }
 }
@@ -1,11 +1,12 @@
 use std::marker::PhantomData;
 
+use analyser::TensorFact;
 use Result;
 use super::{Input, Op};
-use matrix::Datum;
+use matrix::Element;
 
 #[derive(Debug, Default, new)]
-pub struct Pack<T: Datum> {
+pub struct Pack<T: Element> {
     axis: usize,
     _phantom: PhantomData<T>,
 }
@@ -13,12 +14,12 @@ pub struct Pack<T: Datum> {
 pub fn pack(pb: &::tfpb::node_def::NodeDef) -> Result<Box<Op>> {
     let dtype = pb.get_attr_datatype("T")?;
     let axis = pb.get_attr_int("axis")?;
-    Ok(boxed_new!(Pack(dtype)(axis)))
+    Ok(boxed_new_element!(Pack(dtype)(axis)))
 }
 
 impl<T> Op for Pack<T>
 where
-    T: Datum,
+    T: Element,
 {
     fn eval(&self, inputs: Vec<Input>) -> Result<Vec<Input>> {
         use ndarray::Axis;
@@ -29,9 +30,18 @@ where
                 //                Ok(T::mat_to_view(&*m)?)
             })
             .collect::<Result<Vec<_>>>()?;
-        let array = ::ndarray::stack(Axis(self.axis), &*views)?;
+        let array = super::stack(Axis(self.axis), &*views)?;
         Ok(vec![T::array_into_mat(array).into()])
     }
+
+    fn infer(&self, inputs: Vec<&TensorFact>) -> Result<Vec<TensorFact>> {
+        let datatype = inputs.iter().filter_map(|i| i.datatype).next();
+        let shape = inputs.iter().filter_map(|i| i.shape.clone()).next().map(|mut shape| {
+            shape.insert(self.axis, Some(inputs.len()));
+            shape
+        });
+        Ok(vec![TensorFact { datatype, shape, value: None }])
+    }
 }
 
 #[cfg(test)]
@@ -1,4 +1,5 @@
 use ndarray::prelude::*;
+use tfpb::types::DataType::DT_INT32;
 use {Matrix, Result};
 use ops::{Input, Op};
 
@@ -23,10 +24,10 @@ pub struct StridedSlice {
 impl Op for StridedSlice {
     fn eval(&self, mut inputs: Vec<Input>) -> Result<Vec<Input>> {
         let (input, begin, end, strides) = args_4!(inputs);
-        let input = input.as_i32s().ok_or("Input expected as I32")?;
-        let begin = begin.as_i32s().ok_or("Begin expected as I32")?;
-        let end = end.as_i32s().ok_or("End expected as I32")?;
-        let strides = strides.as_i32s().ok_or("Strides expected as I32")?;
+        let input = input.as_i32s().ok_or_else(|| input.mismatch(DT_INT32))?;
+        let begin = begin.as_i32s().ok_or_else(|| begin.mismatch(DT_INT32))?;
+        let end = end.as_i32s().ok_or_else(|| end.mismatch(DT_INT32))?;
+        let strides = strides.as_i32s().ok_or_else(|| strides.mismatch(DT_INT32))?;
         struct Dim {
             begin: i32,
             stride: i32,
@@ -1,4 +1,10 @@
-use Result;
+use half::f16;
+use num_traits::AsPrimitive;
+
+use analyser::TensorFact;
+use errors::ErrorKind;
+use tfpb::types::DataType;
+use {Matrix, Result};
 
 use super::{Input, Op, OpRegister};
 
@@ -7,24 +13,85 @@ pub fn register_all_ops(reg: &mut OpRegister) {
 }
 
 #[derive(Debug)]
-pub struct Cast;
+pub struct Cast {
+    dst: Option<DataType>,
+}
 
 impl Cast {
-    pub fn build(_pb: &::tfpb::node_def::NodeDef) -> Result<Box<Op>> {
-        Ok(Box::new(Cast {}))
+    pub fn build(pb: &::tfpb::node_def::NodeDef) -> Result<Box<Op>> {
+        Ok(Box::new(Cast {
+            dst: pb.get_attr_opt_datatype("DstT")?,
+        }))
     }
 }
 
+macro_rules! cast_to {
+    ($input:expr, $t:ty) => {
+        match $input {
+            &Matrix::F16(ref a) => a.mapv(AsPrimitive::<$t>::as_).into(),
+            &Matrix::F32(ref a) => a.mapv(AsPrimitive::<$t>::as_).into(),
+            &Matrix::F64(ref a) => a.mapv(AsPrimitive::<$t>::as_).into(),
+            &Matrix::I64(ref a) => a.mapv(AsPrimitive::<$t>::as_).into(),
+            &Matrix::I32(ref a) => a.mapv(AsPrimitive::<$t>::as_).into(),
+            &Matrix::I16(ref a) => a.mapv(AsPrimitive::<$t>::as_).into(),
+            &Matrix::I8(ref a) => a.mapv(AsPrimitive::<$t>::as_).into(),
+            &Matrix::U16(ref a) => a.mapv(AsPrimitive::<$t>::as_).into(),
+            &Matrix::U8(ref a) => a.mapv(AsPrimitive::<$t>::as_).into(),
+            &Matrix::Bool(ref a) => a.mapv(|x| AsPrimitive::<$t>::as_(x as u8)).into(),
+            m => Err(ErrorKind::UnsupportedDatatype(String::new(), "Cast".into(), m.datatype()))?,
+        }
+    };
+}
+
 impl ::ops::Op for Cast {
-    fn eval(&self, mut _inputs: Vec<Input>) -> Result<Vec<Input>> {
-        panic!(
-            "nope, fixme. parse two args in build to get src and dst types, then generalize (macro ?)"
-        );
-        /*
-        let input = inputs.remove(0).take_f32s().ok_or(
-            "Expect input #0 to be f32",
-        )?;
-        Ok(vec![Matrix::F32(input.mapv(|i| i as _))])
-        */
+    fn eval(&self, mut inputs: Vec<Input>) -> Result<Vec<Input>> {
+        let input = args_1!(inputs);
+        let output: Matrix = match self.dst.ok_or("Cast: missing DstT attribute")? {
+            DataType::DT_HALF => cast_to!(&*input, f16),
+            DataType::DT_FLOAT => cast_to!(&*input, f32),
+            DataType::DT_DOUBLE => cast_to!(&*input, f64),
+            DataType::DT_INT64 => cast_to!(&*input, i64),
+            DataType::DT_INT32 => cast_to!(&*input, i32),
+            DataType::DT_INT16 => cast_to!(&*input, i16),
+            DataType::DT_INT8 => cast_to!(&*input, i8),
+            DataType::DT_UINT16 => cast_to!(&*input, u16),
+            DataType::DT_UINT8 => cast_to!(&*input, u8),
+            dt => Err(ErrorKind::UnsupportedDatatype(String::new(), "Cast".into(), dt))?,
+        };
+        Ok(vec![output.into()])
+    }
+
+    fn infer(&self, mut inputs: Vec<&TensorFact>) -> Result<Vec<TensorFact>> {
+        let input = args_1!(inputs);
+        Ok(vec![TensorFact {
+            datatype: self.dst,
+            shape: input.shape.clone(),
+            value: None,
+        }])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ops::Op;
+
+    #[test]
+    fn cast() {
+        let cast = Cast {
+            dst: Some(DataType::DT_INT32),
+        };
+        let input = Matrix::f32s(&[3], &[1.5, -2.0, 3.0]).unwrap();
+        let output = cast.eval(vec![input.into()]).unwrap();
+        assert_eq!(*output[0], Matrix::i32s(&[3], &[1, -2, 3]).unwrap());
+        let cast = Cast {
+            dst: Some(DataType::DT_FLOAT),
+        };
+        let mask = Matrix::bools(&[2], &[true, false]).unwrap();
+        let output = cast.eval(vec![mask.into()]).unwrap();
+        assert_eq!(*output[0], Matrix::f32s(&[2], &[1.0, 0.0]).unwrap());
+        let cast = Cast { dst: None };
+        let input = Matrix::f32s(&[1], &[1.0]).unwrap();
+        assert!(cast.eval(vec![input.into()]).is_err());
     }
 }
@@ -0,0 +1,246 @@
+//! Control flow ops.
+//!
+//! Switch, Merge, Enter, Exit, NextIteration and LoopCond only make sense in
+//! the frame-based dataflow executor, which deals with dead tensors and loop
+//! iterations. The kernels here only cover their data path.
+
+use ndarray::ArrayD;
+
+use analyser::TensorFact;
+use matrix::Element;
+use tfpb::types::DataType;
+use {Matrix, Result};
+use super::{Input, Op, OpRegister};
+
+pub fn register_all_ops(reg: &mut OpRegister) {
+    reg.insert("Assert", Assert::build);
+    reg.insert("Enter", Enter::build);
+    reg.insert("Exit", Exit::build);
+    reg.insert("LoopCond", LoopCond::build);
+    reg.insert("Merge", Merge::build);
+    reg.insert("NextIteration", NextIteration::build);
+    reg.insert("NoOp", NoOp::build);
+    reg.insert("Switch", Switch::build);
+}
+
+/// Names of the ops requiring the dataflow executor.
+pub const CONTROL_FLOW_OPS: &[&str] = &[
+    "Enter",
+    "Exit",
+    "LoopCond",
+    "Merge",
+    "NextIteration",
+    "Switch",
+];
+
+/// Read a boolean predicate from a scalar tensor.
+pub fn predicate(m: &Matrix) -> Result<bool> {
+    fn scalar<T: Copy + ::std::fmt::Debug>(a: &ArrayD<T>) -> Result<T> {
+        if a.len() != 1 {
+            Err(format!("Expected a scalar predicate, got shape {:?}", a.shape()))?
+        }
+        Ok(*a.iter().next().unwrap())
+    }
+    Ok(match m {
+        &Matrix::Bool(ref a) => scalar(a)?,
+        &Matrix::F32(ref a) => scalar(a)? != 0.0,
+        &Matrix::F64(ref a) => scalar(a)? != 0.0,
+        &Matrix::I64(ref a) => scalar(a)? != 0,
+        &Matrix::I32(ref a) => scalar(a)? != 0,
+        &Matrix::I8(ref a) => scalar(a)? != 0,
+        &Matrix::U8(ref a) => scalar(a)? != 0,
+        _ => Err("Unsupported predicate type")?,
+    })
+}
+
+#[derive(Debug)]
+pub struct Enter {
+    pub frame: String,
+    pub is_constant: bool,
+}
+
+impl Enter {
+    pub fn build(pb: &::tfpb::node_def::NodeDef) -> Result<Box<Op>> {
+        Ok(Box::new(Enter {
+            frame: pb.get_attr_str("frame_name")?,
+            is_constant: pb.get_attr_opt_bool("is_constant")?.unwrap_or(false),
+        }))
+    }
+}
+
+impl Op for Enter {
+    fn eval(&self, mut inputs: Vec<Input>) -> Result<Vec<Input>> {
+        Ok(vec![args_1!(inputs)])
+    }
+
+    fn infer(&self, mut inputs: Vec<&TensorFact>) -> Result<Vec<TensorFact>> {
+        Ok(vec![args_1!(inputs).clone()])
+    }
+
+    fn frame(&self) -> Option<(&str, bool)> {
+        Some((&self.frame, self.is_constant))
+    }
+}
+
+macro_rules! forward {
+    ($Struct:ident) => {
+        #[derive(Debug)]
+        pub struct $Struct;
+
+        impl $Struct {
+            pub fn build(_pb: &::tfpb::node_def::NodeDef) -> Result<Box<Op>> {
+                Ok(Box::new($Struct))
+            }
+        }
+
+        impl Op for $Struct {
+            fn eval(&self, mut inputs: Vec<Input>) -> Result<Vec<Input>> {
+                Ok(vec![args_1!(inputs)])
+            }
+
+            fn infer(&self, mut inputs: Vec<&TensorFact>) -> Result<Vec<TensorFact>> {
+                Ok(vec![args_1!(inputs).clone()])
+            }
+        }
+    }
+}
+
+forward!(Exit);
+forward!(LoopCond);
+forward!(NextIteration);
+
+/// Forwards its first input, with the index of this input.
+///
+/// In the dataflow executor, Merge forwards the first live input it gets.
+#[derive(Debug)]
+pub struct Merge;
+
+impl Merge {
+    pub fn build(_pb: &::tfpb::node_def::NodeDef) -> Result<Box<Op>> {
+        Ok(Box::new(Merge))
+    }
+}
+
+impl Op for Merge {
+    fn eval(&self, mut inputs: Vec<Input>) -> Result<Vec<Input>> {
+        if inputs.is_empty() {
+            Err("Merge expects at least one input")?
+        }
+        Ok(vec![inputs.remove(0), Matrix::i32s(&[], &[0])?.into()])
+    }
+
+    /// The first input with a known shape gives the output fact, as the
+    /// others may come from a later loop iteration.
+    fn infer(&self, inputs: Vec<&TensorFact>) -> Result<Vec<TensorFact>> {
+        let fact = inputs
+            .iter()
+            .find(|i| i.shape.is_some())
+            .or(inputs.first())
+            .map(|&i| TensorFact {
+                value: None,
+                ..i.clone()
+            })
+            .unwrap_or_default();
+        Ok(vec![fact, TensorFact::shaped(Some(DataType::DT_INT32), vec![])])
+    }
+}
+
+/// Forwards its data input to output 1 if the predicate is true, to output 0
+/// otherwise. The other output is dead.
+#[derive(Debug)]
+pub struct Switch;
+
+impl Switch {
+    pub fn build(_pb: &::tfpb::node_def::NodeDef) -> Result<Box<Op>> {
+        Ok(Box::new(Switch))
+    }
+}
+
+impl Op for Switch {
+    fn eval(&self, _inputs: Vec<Input>) -> Result<Vec<Input>> {
+        Err("Switch can only be evaluated by the dataflow executor")?
+    }
+
+    fn infer(&self, mut inputs: Vec<&TensorFact>) -> Result<Vec<TensorFact>> {
+        let (data, _) = args_2!(inputs);
+        Ok(vec![data.clone(), data.clone()])
+    }
+}
+
+#[derive(Debug)]
+pub struct NoOp;
+
+impl NoOp {
+    pub fn build(_pb: &::tfpb::node_def::NodeDef) -> Result<Box<Op>> {
+        Ok(Box::new(NoOp))
+    }
+}
+
+impl Op for NoOp {
+    fn eval(&self, _inputs: Vec<Input>) -> Result<Vec<Input>> {
+        Ok(vec![])
+    }
+
+    fn infer(&self, _inputs: Vec<&TensorFact>) -> Result<Vec<TensorFact>> {
+        Ok(vec![])
+    }
+}
+
+/// Assert has no output, and is only ever used as a control dependency.
+///
+/// Fails if its boolean condition does not hold, with the strings among its
+/// data inputs as message.
+#[derive(Debug)]
+pub struct Assert;
+
+impl Assert {
+    pub fn build(_pb: &::tfpb::node_def::NodeDef) -> Result<Box<Op>> {
+        Ok(Box::new(Assert))
+    }
+}
+
+impl Op for Assert {
+    fn eval(&self, inputs: Vec<Input>) -> Result<Vec<Input>> {
+        let cond = match inputs.first() {
+            Some(cond) => bool::mat_to_view(cond)?,
+            None => Err("Assert expects a condition")?,
+        };
+        if !cond.iter().all(|&c| c) {
+            let message: Vec<String> = inputs[1..]
+                .iter()
+                .filter_map(|i| i.as_strings())
+                .flat_map(|s| s.iter().map(|s| String::from_utf8_lossy(s).into_owned()))
+                .collect();
+            Err(format!("Assertion failed: {}", message.join(" ")))?
+        }
+        Ok(vec![])
+    }
+
+    fn infer(&self, _inputs: Vec<&TensorFact>) -> Result<Vec<TensorFact>> {
+        Ok(vec![])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bool_predicate() {
+        assert!(predicate(&Matrix::bools(&[], &[true]).unwrap()).unwrap());
+        assert!(!predicate(&Matrix::bools(&[1], &[false]).unwrap()).unwrap());
+        assert!(predicate(&Matrix::bools(&[2], &[true, true]).unwrap()).is_err());
+    }
+
+    #[test]
+    fn assert() {
+        let message = Matrix::strings(&[], &[b"x must be positive".to_vec()]).unwrap();
+        let holds = Matrix::bools(&[], &[true]).unwrap();
+        assert!(Assert.eval(vec![holds.into(), message.clone().into()]).is_ok());
+        let fails = Matrix::bools(&[], &[false]).unwrap();
+        let err = Assert.eval(vec![fails.into(), message.into()]).err().unwrap();
+        assert!(err.to_string().contains("x must be positive"));
+        let not_bool = Matrix::f32s(&[], &[1.0]).unwrap();
+        assert!(Assert.eval(vec![not_bool.into()]).is_err());
+    }
+}
@@ -1,29 +1,39 @@
 use ndarray::prelude::*;
 
+use matrix::Element;
+use tfpb::types::DataType::*;
 use {Matrix, Result};
-use super::{Input, Op};
+use super::{Input, Op, OpRegister};
+
+pub fn register_all_ops(reg: &mut OpRegister) {
+    reg.insert("DecodeJpeg", DecodeJpeg::build);
+    reg.insert("ResizeBilinear", ResizeBilinear::build);
+}
 
 #[derive(Debug)]
 pub struct DecodeJpeg {}
 
 impl DecodeJpeg {
-    pub fn build(_pb: &::tfpb::node_def::NodeDef) -> Result<DecodeJpeg> {
-        Ok(DecodeJpeg {})
+    pub fn build(_pb: &::tfpb::node_def::NodeDef) -> Result<Box<Op>> {
+        Ok(Box::new(DecodeJpeg {}))
     }
 }
 
+/// Decode an image to a HWC array of RGB pixels.
 pub fn decode_one(input: &[u8]) -> Result<Array3<u8>> {
-    use image::GenericImage;
-    let image = ::image::load_from_memory(input)?;
-    let dim = image.dimensions();
-    Ok(Array1::from_vec(image.raw_pixels()).into_shape((dim.0 as usize, dim.1 as usize, 3))?)
+    let image = ::image::load_from_memory(input)?.to_rgb();
+    let (width, height) = image.dimensions();
+    Ok(Array1::from_vec(image.into_raw()).into_shape((height as usize, width as usize, 3))?)
 }
 
 impl Op for DecodeJpeg {
     fn eval(&self, mut inputs: Vec<Input>) -> Result<Vec<Input>> {
         let m_input = args_1!(inputs);
-        let input = m_input.as_u8s().ok_or("Expected a string")?;
-        let image = decode_one(input.as_slice().unwrap())?;
+        let input = Vec::<u8>::mat_to_view(&m_input)?;
+        if input.ndim() != 0 {
+            Err(format!("DecodeJpeg expects a scalar string, got shape {:?}", input.shape()))?
+        }
+        let image = decode_one(input.iter().next().unwrap())?;
         Ok(vec![Matrix::U8(image.into_dyn()).into()])
     }
 }
@@ -32,8 +42,8 @@ impl Op for DecodeJpeg {
 pub struct ResizeBilinear {}
 
 impl ResizeBilinear {
-    pub fn build(_pb: &::tfpb::node_def::NodeDef) -> Result<ResizeBilinear> {
-        Ok(ResizeBilinear {})
+    pub fn build(_pb: &::tfpb::node_def::NodeDef) -> Result<Box<Op>> {
+        Ok(Box::new(ResizeBilinear {}))
     }
 }
 
@@ -41,11 +51,8 @@ impl Op for ResizeBilinear {
     fn eval(&self, mut inputs: Vec<Input>) -> Result<Vec<Input>> {
         use std::cmp::min;
         let (m_images, m_sizes) = args_2!(inputs);
-        let images = m_images
-            .into_matrix()
-            .take_f32s()
-            .ok_or("Expect input #0 to be images")?;
-        let sizes = m_sizes.as_i32s().ok_or("Expect input #1 to be sizes")?;
+        let images = f32::mat_into_array(m_images.into_matrix())?;
+        let sizes = m_sizes.as_i32s().ok_or_else(|| m_sizes.mismatch(DT_INT32))?;
         let batches = images.shape()[0];
         let old_height = images.shape()[1];
         let old_width = images.shape()[2];
@@ -75,3 +82,21 @@ impl Op for ResizeBilinear {
         Ok(vec![Matrix::F32(result.into_dyn()).into()])
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_jpeg() {
+        let jpeg = include_bytes!("../../exs/inceptionv3/grace_hopper.jpg").to_vec();
+        let input = Matrix::String(arr0(jpeg.clone()).into_dyn());
+        let output = DecodeJpeg {}.eval(vec![input.into()]).unwrap();
+        match *output[0] {
+            Matrix::U8(ref image) => assert_eq!(image.shape(), &[606, 517, 3]),
+            ref m => panic!("unexpected output {:?}", m.datatype()),
+        }
+        let batch = Matrix::String(arr1(&[jpeg]).into_dyn());
+        assert!(DecodeJpeg {}.eval(vec![batch.into()]).is_err());
+    }
+}
@@ -1,3 +1,4 @@
+use analyser::TensorFact;
 use {Matrix, Result};
 use super::{Input, Op, OpRegister};
 use std::sync::Arc;
@@ -6,7 +7,7 @@ pub fn register_all_ops(reg: &mut OpRegister) {
     reg.insert("Const", Const::build);
 }
 
-#[derive(Debug)]
+#[derive(Debug, new)]
 pub struct Const {
     value: Arc<Matrix>,
 }
@@ -24,4 +25,8 @@ impl Op for Const {
     fn eval(&self, _inputs: Vec<Input>) -> Result<Vec<Input>> {
         Ok(vec![self.value.clone().into()])
     }
+
+    fn infer(&self, _inputs: Vec<&TensorFact>) -> Result<Vec<TensorFact>> {
+        Ok(vec![TensorFact::from_value(self.value.as_ref().clone())])
+    }
 }
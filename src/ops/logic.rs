@@ -0,0 +1,246 @@
+//! Comparisons and logical ops on boolean tensors.
+
+use std::marker::PhantomData;
+
+use ndarray::prelude::*;
+use ndarray::Zip;
+
+use analyser::{broadcast, TensorFact};
+use matrix::{Datum, Element};
+use tfpb::types::DataType;
+use {Matrix, Result};
+use super::{Input, Op, OpRegister};
+
+pub fn register_all_ops(reg: &mut OpRegister) {
+    reg.insert("Equal", equal);
+    reg.insert("NotEqual", not_equal);
+    reg.insert("Less", less);
+    reg.insert("LessEqual", less_equal);
+    reg.insert("Greater", greater);
+    reg.insert("GreaterEqual", greater_equal);
+    reg.insert("LogicalAnd", LogicalAnd::build);
+    reg.insert("LogicalOr", LogicalOr::build);
+    reg.insert("LogicalNot", LogicalNot::build);
+    reg.insert("Select", select);
+}
+
+/// Apply `f` to the elements of `a` and `b`, broadcast to a common shape.
+fn zip_broadcast<A, B, C, F>(a: ArrayViewD<A>, b: ArrayViewD<B>, f: F) -> Result<ArrayD<C>>
+where
+    F: Fn(&A, &B) -> C,
+{
+    let dims = |shape: &[usize]| shape.iter().map(|&d| Some(d)).collect::<Vec<_>>();
+    let shape: Vec<usize> = broadcast(&dims(a.shape()), &dims(b.shape()))?
+        .into_iter()
+        .flatten()
+        .collect();
+    let (a, b) = match (a.broadcast(&*shape), b.broadcast(&*shape)) {
+        (Some(a), Some(b)) => (a, b),
+        _ => Err(format!("Can not broadcast {:?} and {:?}", a.shape(), b.shape()))?,
+    };
+    let data = a.iter().zip(b.iter()).map(|(a, b)| f(a, b)).collect();
+    Ok(ArrayD::from_shape_vec(shape, data)?)
+}
+
+fn infer_bool_bin(mut inputs: Vec<&TensorFact>) -> Result<Vec<TensorFact>> {
+    let (a, b) = args_2!(inputs);
+    let shape = match (&a.shape, &b.shape) {
+        (&Some(ref a), &Some(ref b)) => Some(broadcast(a, b)?),
+        _ => None,
+    };
+    Ok(vec![TensorFact {
+        datatype: Some(DataType::DT_BOOL),
+        shape,
+        value: None,
+    }])
+}
+
+macro_rules! comparison {
+    ($Name:ident, $name:ident, $Bound:ident, $boxed_new:ident, $op:tt) => {
+        #[derive(Debug, new)]
+        pub struct $Name<T: $Bound>(PhantomData<T>);
+
+        pub fn $name(pb: &::tfpb::node_def::NodeDef) -> Result<Box<Op>> {
+            let dtype = pb.get_attr_datatype("T")?;
+            Ok($boxed_new!($Name(dtype)()))
+        }
+
+        impl<T: $Bound> Op for $Name<T> {
+            fn eval(&self, mut inputs: Vec<Input>) -> Result<Vec<Input>> {
+                let (a, b) = args_2!(inputs);
+                let c = zip_broadcast(T::mat_to_view(&a)?, T::mat_to_view(&b)?, |a, b| a $op b)?;
+                Ok(vec![Matrix::from(c).into()])
+            }
+
+            fn infer(&self, inputs: Vec<&TensorFact>) -> Result<Vec<TensorFact>> {
+                infer_bool_bin(inputs)
+            }
+        }
+    };
+}
+
+comparison!(Equal, equal, Element, boxed_new_element, ==);
+comparison!(NotEqual, not_equal, Element, boxed_new_element, !=);
+comparison!(Less, less, Datum, boxed_new, <);
+comparison!(LessEqual, less_equal, Datum, boxed_new, <=);
+comparison!(Greater, greater, Datum, boxed_new, >);
+comparison!(GreaterEqual, greater_equal, Datum, boxed_new, >=);
+
+macro_rules! logical {
+    ($Name:ident, $op:tt) => {
+        #[derive(Debug)]
+        pub struct $Name;
+
+        impl $Name {
+            pub fn build(_pb: &::tfpb::node_def::NodeDef) -> Result<Box<Op>> {
+                Ok(Box::new($Name))
+            }
+        }
+
+        impl Op for $Name {
+            fn eval(&self, mut inputs: Vec<Input>) -> Result<Vec<Input>> {
+                let (a, b) = args_2!(inputs);
+                let (a, b) = (bool::mat_to_view(&a)?, bool::mat_to_view(&b)?);
+                Ok(vec![Matrix::from(zip_broadcast(a, b, |&a, &b| a $op b)?).into()])
+            }
+
+            fn infer(&self, inputs: Vec<&TensorFact>) -> Result<Vec<TensorFact>> {
+                infer_bool_bin(inputs)
+            }
+        }
+    };
+}
+
+logical!(LogicalAnd, &&);
+logical!(LogicalOr, ||);
+
+#[derive(Debug)]
+pub struct LogicalNot;
+
+impl LogicalNot {
+    pub fn build(_pb: &::tfpb::node_def::NodeDef) -> Result<Box<Op>> {
+        Ok(Box::new(LogicalNot))
+    }
+}
+
+impl Op for LogicalNot {
+    fn eval(&self, mut inputs: Vec<Input>) -> Result<Vec<Input>> {
+        let a = args_1!(inputs);
+        Ok(vec![Matrix::from(bool::mat_to_view(&a)?.mapv(|a| !a)).into()])
+    }
+
+    fn infer(&self, mut inputs: Vec<&TensorFact>) -> Result<Vec<TensorFact>> {
+        let a = args_1!(inputs);
+        Ok(vec![TensorFact {
+            datatype: Some(DataType::DT_BOOL),
+            shape: a.shape.clone(),
+            value: None,
+        }])
+    }
+}
+
+/// Elements of `t` where the condition holds, of `e` elsewhere. A 1D
+/// condition selects whole rows along the first axis.
+#[derive(Debug, new)]
+pub struct Select<T: Element>(PhantomData<T>);
+
+pub fn select(pb: &::tfpb::node_def::NodeDef) -> Result<Box<Op>> {
+    let dtype = pb.get_attr_datatype("T")?;
+    Ok(boxed_new_element!(Select(dtype)()))
+}
+
+impl<T: Element> Op for Select<T> {
+    fn eval(&self, mut inputs: Vec<Input>) -> Result<Vec<Input>> {
+        let (cond, t, e) = args_3!(inputs);
+        let cond = bool::mat_to_view(&cond)?;
+        let (t, e) = (T::mat_to_view(&t)?, T::mat_to_view(&e)?);
+        if t.shape() != e.shape() {
+            Err(format!("Select: branches have shapes {:?} and {:?}", t.shape(), e.shape()))?
+        }
+        let mut output = e.to_owned();
+        if cond.shape() == t.shape() {
+            Zip::from(&mut output)
+                .and(&cond)
+                .and(&t)
+                .apply(|o, &c, t| if c { *o = t.clone() });
+        } else if cond.ndim() == 1 && t.ndim() > 0 && cond.len() == t.shape()[0] {
+            for (i, _) in cond.iter().enumerate().filter(|&(_, &c)| c) {
+                output.subview_mut(Axis(0), i).assign(&t.subview(Axis(0), i));
+            }
+        } else {
+            Err(format!(
+                "Select: condition of shape {:?} does not match {:?}",
+                cond.shape(),
+                t.shape()
+            ))?
+        }
+        Ok(vec![T::array_into_mat(output).into()])
+    }
+
+    fn infer(&self, mut inputs: Vec<&TensorFact>) -> Result<Vec<TensorFact>> {
+        let (_, t, e) = args_3!(inputs);
+        Ok(vec![TensorFact {
+            datatype: t.datatype.or(e.datatype),
+            shape: t.shape.clone().or_else(|| e.shape.clone()),
+            value: None,
+        }])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comparisons() {
+        let a = Matrix::f32s(&[2, 2], &[1.0, 2.0, 3.0, 4.0]).unwrap();
+        let b = Matrix::f32s(&[2], &[2.0, 3.0]).unwrap();
+        let less = Less::<f32>::new().eval(vec![a.clone().into(), b.into()]).unwrap();
+        assert_eq!(*less[0], Matrix::bools(&[2, 2], &[true, true, false, false]).unwrap());
+
+        let words = Matrix::strings(&[2], &[b"a".to_vec(), b"b".to_vec()]).unwrap();
+        let word = Matrix::strings(&[], &[b"b".to_vec()]).unwrap();
+        let equal = Equal::<Vec<u8>>::new().eval(vec![words.into(), word.into()]).unwrap();
+        assert_eq!(*equal[0], Matrix::bools(&[2], &[false, true]).unwrap());
+
+        let c = Matrix::f32s(&[3], &[1.0, 2.0, 3.0]).unwrap();
+        assert!(Less::<f32>::new().eval(vec![a.into(), c.into()]).is_err());
+    }
+
+    #[test]
+    fn logical() {
+        let a = Matrix::bools(&[4], &[true, true, false, false]).unwrap();
+        let b = Matrix::bools(&[4], &[true, false, true, false]).unwrap();
+        let and = LogicalAnd.eval(vec![a.clone().into(), b.clone().into()]).unwrap();
+        assert_eq!(*and[0], Matrix::bools(&[4], &[true, false, false, false]).unwrap());
+        let or = LogicalOr.eval(vec![a.clone().into(), b.into()]).unwrap();
+        assert_eq!(*or[0], Matrix::bools(&[4], &[true, true, true, false]).unwrap());
+        let not = LogicalNot.eval(vec![a.into()]).unwrap();
+        assert_eq!(*not[0], Matrix::bools(&[4], &[false, false, true, true]).unwrap());
+    }
+
+    #[test]
+    fn select() {
+        let t = Matrix::i32s(&[2, 2], &[1, 2, 3, 4]).unwrap();
+        let e = Matrix::i32s(&[2, 2], &[-1, -2, -3, -4]).unwrap();
+        let cond = Matrix::bools(&[2, 2], &[true, false, false, true]).unwrap();
+        let found = Select::<i32>::new()
+            .eval(vec![cond.into(), t.clone().into(), e.clone().into()])
+            .unwrap();
+        assert_eq!(*found[0], Matrix::i32s(&[2, 2], &[1, -2, -3, 4]).unwrap());
+
+        let rows = Matrix::bools(&[2], &[false, true]).unwrap();
+        let found = Select::<i32>::new()
+            .eval(vec![rows.into(), t.into(), e.into()])
+            .unwrap();
+        assert_eq!(*found[0], Matrix::i32s(&[2, 2], &[-1, -2, 3, 4]).unwrap());
+    }
+
+    #[test]
+    fn bool_tensor_proto() {
+        let mask = Matrix::bools(&[3], &[true, false, true]).unwrap();
+        let pb = mask.to_pb().unwrap();
+        assert_eq!(pb.get_bool_val(), &[true, false, true]);
+        assert_eq!(Matrix::from_pb(&pb).unwrap(), mask);
+    }
+}
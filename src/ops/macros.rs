@@ -12,12 +12,22 @@ macro_rules! element_map {
         impl ::ops::Op for $Struct {
             fn eval(&self, mut inputs: Vec<$crate::ops::Input>) -> $crate::Result<Vec<$crate::ops::Input>> {
                 let a = args_1!(inputs);
-                let mut a = a.into_matrix().take_f32s().ok_or(
-                    "Expect input #0 to be f32",
-                )?;
+                let mut a = <f32 as $crate::matrix::Element>::mat_into_array(a.into_matrix())?;
                 a.mapv_inplace($expr);
                 Ok(vec![$crate::matrix::Matrix::F32(a).into()])
             }
+
+            fn infer(
+                &self,
+                mut inputs: Vec<&$crate::analyser::TensorFact>,
+            ) -> $crate::Result<Vec<$crate::analyser::TensorFact>> {
+                let a = args_1!(inputs);
+                Ok(vec![$crate::analyser::TensorFact {
+                    datatype: Some($crate::tfpb::types::DataType::DT_FLOAT),
+                    shape: a.shape.clone(),
+                    value: None,
+                }])
+            }
         }
     }
 }
@@ -38,7 +48,32 @@ macro_rules! element_bin {
                 let (a, b) = args_2!(inputs);
                 let a = T::mat_into_array(a.into_matrix())?;
                 let b = T::mat_to_view(&*b)?;
-                Ok(vec!(T::array_into_mat($expr(a,b)).into()))
+                if b.broadcast(a.shape()).is_none() {
+                    Err($crate::errors::ErrorKind::ShapeMismatch(
+                        String::new(),
+                        String::new(),
+                        a.shape().iter().map(|&d| Some(d)).collect(),
+                        b.shape().to_vec(),
+                    ))?
+                }
+                let c: Result<::ndarray::ArrayD<T>> = $expr(a, b);
+                Ok(vec!(T::array_into_mat(c?).into()))
+            }
+
+            fn infer(
+                &self,
+                mut inputs: Vec<&$crate::analyser::TensorFact>,
+            ) -> Result<Vec<$crate::analyser::TensorFact>> {
+                let (a, b) = args_2!(inputs);
+                let shape = match (&a.shape, &b.shape) {
+                    (&Some(ref a), &Some(ref b)) => Some($crate::analyser::broadcast(a, b)?),
+                    _ => None,
+                };
+                Ok(vec![$crate::analyser::TensorFact {
+                    datatype: a.datatype.or(b.datatype),
+                    shape,
+                    value: None,
+                }])
             }
         }
     }
@@ -88,11 +123,60 @@ macro_rules! args_4 {
 macro_rules! boxed_new {
     ($op:tt($dtype:expr)($($arg:expr),*)) => { {
         use tfpb::types::DataType;
-        match $dtype {
+        let dtype = $dtype;
+        match dtype {
+            DataType::DT_INT64 => Box::new($op::<i64>::new($($arg),*)) as Box<Op>,
             DataType::DT_INT32 => Box::new($op::<i32>::new($($arg),*)) as Box<Op>,
+            DataType::DT_INT16 => Box::new($op::<i16>::new($($arg),*)) as Box<Op>,
+            DataType::DT_INT8 => Box::new($op::<i8>::new($($arg),*)) as Box<Op>,
+            DataType::DT_UINT16 => Box::new($op::<u16>::new($($arg),*)) as Box<Op>,
+            DataType::DT_UINT8 => Box::new($op::<u8>::new($($arg),*)) as Box<Op>,
+            DataType::DT_HALF => Box::new($op::<::half::f16>::new($($arg),*)) as Box<Op>,
             DataType::DT_FLOAT => Box::new($op::<f32>::new($($arg),*)) as Box<Op>,
             DataType::DT_DOUBLE => Box::new($op::<f64>::new($($arg),*)) as Box<Op>,
-            _ => unimplemented!()
+            _ => Err($crate::errors::ErrorKind::UnsupportedDatatype(
+                String::new(),
+                stringify!($op).to_string(),
+                dtype,
+            ))?,
+        }
+    } }
+}
+
+/// Like `boxed_new!`, for ops that also accept non-numeric elements.
+macro_rules! boxed_new_element {
+    ($op:tt($dtype:expr)($($arg:expr),*)) => { {
+        use tfpb::types::DataType;
+        match $dtype {
+            DataType::DT_BOOL => Box::new($op::<bool>::new($($arg),*)) as Box<Op>,
+            DataType::DT_STRING => Box::new($op::<Vec<u8>>::new($($arg),*)) as Box<Op>,
+            dtype => boxed_new!($op(dtype)($($arg),*)),
+        }
+    } }
+}
+
+/// Call a function generic over `Element` for the type matching `$dtype`.
+macro_rules! dispatch_element {
+    ($op:ident::$f:ident($dtype:expr)($($arg:expr),*)) => { {
+        use tfpb::types::DataType;
+        let dtype = $dtype;
+        match dtype {
+            DataType::DT_HALF => $op::$f::<::half::f16>($($arg),*),
+            DataType::DT_FLOAT => $op::$f::<f32>($($arg),*),
+            DataType::DT_DOUBLE => $op::$f::<f64>($($arg),*),
+            DataType::DT_INT64 => $op::$f::<i64>($($arg),*),
+            DataType::DT_INT32 => $op::$f::<i32>($($arg),*),
+            DataType::DT_INT16 => $op::$f::<i16>($($arg),*),
+            DataType::DT_INT8 => $op::$f::<i8>($($arg),*),
+            DataType::DT_UINT16 => $op::$f::<u16>($($arg),*),
+            DataType::DT_UINT8 => $op::$f::<u8>($($arg),*),
+            DataType::DT_BOOL => $op::$f::<bool>($($arg),*),
+            DataType::DT_STRING => $op::$f::<Vec<u8>>($($arg),*),
+            _ => Err($crate::errors::ErrorKind::UnsupportedDatatype(
+                String::new(),
+                stringify!($op).to_string(),
+                dtype,
+            ))?,
         }
     } }
 }
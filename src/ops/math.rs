@@ -1,5 +1,13 @@
+use std::marker::PhantomData;
+
+use ndarray::prelude::*;
+use ndarray::Zip;
+
+use analyser::TensorFact;
+use errors::ErrorKind;
+use matrix::Datum;
 use Result;
-use super::{Op, OpRegister};
+use super::{Input, Op, OpRegister};
 
 pub fn register_all_ops(reg: &mut OpRegister) {
     reg.insert("Abs", Abs::build);
@@ -8,6 +16,7 @@ pub fn register_all_ops(reg: &mut OpRegister) {
     reg.insert("Div", div);
     reg.insert("Mul", mul);
     reg.insert("FloorMod", rem);
+    reg.insert("MatMul", matmul);
     reg.insert("Rsqrt", Rsqrt::build);
     reg.insert("Sub", sub);
 }
@@ -17,24 +26,163 @@ element_map!(Abs, |x: f32| x.abs());
 
 element_bin!(Add, add, |mut a, b| {
     a += &b;
-    a
-});
-element_bin!(Div, div, |mut a, b| {
-    a /= &b;
-    a
+    Ok(a)
 });
+element_bin!(Div, div, |a, b| checked(a, b, Datum::checked_div));
 element_bin!(Mul, mul, |mut a, b| {
     a *= &b;
-    a
+    Ok(a)
 });
 element_bin!(Sub, sub, |mut a, b| {
     a -= &b;
-    a
-});
-element_bin!(Rem, rem, |mut a, b| {
-    a %= &b;
-    a
+    Ok(a)
 });
+element_bin!(Rem, rem, |a, b| checked(a, b, Datum::checked_rem));
+
+/// Apply `f` to the elements of `a` and of `b` broadcast to the shape of
+/// `a`, failing if `f` does for any of them.
+fn checked<T: Datum>(
+    mut a: ArrayD<T>,
+    b: ArrayViewD<T>,
+    f: fn(T, T) -> Option<T>,
+) -> Result<ArrayD<T>> {
+    let mut failed = false;
+    {
+        let b = b.broadcast(a.shape())
+            .ok_or_else(|| format!("Can not broadcast {:?} to {:?}", b.shape(), a.shape()))?;
+        Zip::from(&mut a).and(&b).apply(|a, &b| match f(*a, b) {
+            Some(x) => *a = x,
+            None => failed = true,
+        });
+    }
+    if failed {
+        Err("Integer division by zero or overflow")?
+    }
+    Ok(a)
+}
+
+/// Check that `a` and `b` can be multiplied, `b` having as many rows as `a`
+/// has columns.
+fn check_product<T>(a: &ArrayView2<T>, b: &ArrayView2<T>) -> Result<()> {
+    if a.cols() != b.rows() {
+        Err(ErrorKind::ShapeMismatch(
+            String::new(),
+            String::new(),
+            vec![Some(a.cols()), None],
+            b.shape().to_vec(),
+        ))?
+    }
+    Ok(())
+}
+
+/// Compute `a.b + bias`, the bias being broadcast over the rows, and apply a
+/// Relu if `relu` is set.
+///
+/// The output is initialized with the bias, the product being accumulated
+/// on top of it by the GEMM, and the Relu applied in the same buffer.
+pub fn gemm_bias_relu<T: Datum>(
+    a: ArrayView2<T>,
+    b: ArrayView2<T>,
+    bias: ArrayView1<T>,
+    relu: bool,
+) -> Result<Array2<T>> {
+    check_product(&a, &b)?;
+    let shape = (a.rows(), b.cols());
+    let mut c = bias.broadcast(shape)
+        .ok_or_else(|| format!("Can not broadcast bias of shape {:?} to {:?}", bias.shape(), shape))?
+        .to_owned();
+    ::ndarray::linalg::general_mat_mul(T::one(), &a, &b, T::one(), &mut c);
+    if relu {
+        c.mapv_inplace(|x| if x < T::zero() { T::zero() } else { x });
+    }
+    Ok(c)
+}
+
+#[derive(Debug, Clone, new)]
+pub struct MatMul<T: Datum> {
+    transpose_a: bool,
+    transpose_b: bool,
+    _phantom: PhantomData<T>,
+}
+
+pub fn matmul(pb: &::tfpb::node_def::NodeDef) -> Result<Box<Op>> {
+    let dtype = pb.get_attr_datatype("T")?;
+    let transpose_a = pb.get_attr_opt_bool("transpose_a")?.unwrap_or(false);
+    let transpose_b = pb.get_attr_opt_bool("transpose_b")?.unwrap_or(false);
+    Ok(boxed_new!(MatMul(dtype)(transpose_a, transpose_b)))
+}
+
+impl<T: Datum> MatMul<T> {
+    fn operands<'a>(
+        &self,
+        a: &'a Input,
+        b: &'a Input,
+    ) -> Result<(ArrayView2<'a, T>, ArrayView2<'a, T>)> {
+        let a = T::mat_to_view(a)?.into_dimensionality::<Ix2>()?;
+        let b = T::mat_to_view(b)?.into_dimensionality::<Ix2>()?;
+        let a = if self.transpose_a { a.reversed_axes() } else { a };
+        let b = if self.transpose_b { b.reversed_axes() } else { b };
+        check_product(&a, &b)?;
+        Ok((a, b))
+    }
+
+    fn infer_shape(&self, a: &TensorFact, b: &TensorFact) -> Result<TensorFact> {
+        let datatype = a.datatype.or(b.datatype);
+        let dims = |f: &TensorFact, transpose: bool| -> Result<(Option<usize>, Option<usize>)> {
+            match f.shape {
+                Some(ref s) if s.len() != 2 => Err(format!("Expected a matrix, got {:?}", s))?,
+                Some(ref s) if transpose => Ok((s[1], s[0])),
+                Some(ref s) => Ok((s[0], s[1])),
+                None => Ok((None, None)),
+            }
+        };
+        let (m, _) = dims(a, self.transpose_a)?;
+        let (_, n) = dims(b, self.transpose_b)?;
+        Ok(TensorFact::shaped(datatype, vec![m, n]))
+    }
+}
+
+impl<T: Datum> Op for MatMul<T> {
+    fn eval(&self, mut inputs: Vec<Input>) -> Result<Vec<Input>> {
+        let (a, b) = args_2!(inputs);
+        let (a, b) = self.operands(&a, &b)?;
+        Ok(vec![T::array_into_mat(a.dot(&b).into_dyn()).into()])
+    }
+
+    fn infer(&self, mut inputs: Vec<&TensorFact>) -> Result<Vec<TensorFact>> {
+        let (a, b) = args_2!(inputs);
+        Ok(vec![self.infer_shape(a, b)?])
+    }
+
+    fn fuse_bias_relu(&self, relu: bool) -> Option<Box<Op>> {
+        Some(Box::new(FusedMatMul {
+            matmul: self.clone(),
+            relu,
+        }))
+    }
+}
+
+/// MatMul followed by a bias addition and an optional Relu.
+#[derive(Debug)]
+pub struct FusedMatMul<T: Datum> {
+    matmul: MatMul<T>,
+    relu: bool,
+}
+
+impl<T: Datum> Op for FusedMatMul<T> {
+    fn eval(&self, mut inputs: Vec<Input>) -> Result<Vec<Input>> {
+        let (a, b, bias) = args_3!(inputs);
+        let (a, b) = self.matmul.operands(&a, &b)?;
+        let bias = T::mat_to_view(&bias)?.into_dimensionality::<Ix1>()?;
+        let c = gemm_bias_relu(a, b, bias, self.relu)?;
+        Ok(vec![T::array_into_mat(c.into_dyn()).into()])
+    }
+
+    fn infer(&self, mut inputs: Vec<&TensorFact>) -> Result<Vec<TensorFact>> {
+        let (a, b, _) = args_3!(inputs);
+        Ok(vec![self.matmul.infer_shape(a, b)?])
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -51,4 +199,74 @@ mod tests {
         let b = arr2(&[[1., 0.], [0., 0.]]);
         assert_eq!(a.dot(&b), arr2(&[[1., 0.], [3., 0.]]));
     }
+
+    #[test]
+    fn gemm_bias_relu() {
+        let a = arr2(&[[1., 2.], [3., 4.]]);
+        let b = arr2(&[[1., -1.], [0., -1.]]);
+        let bias = ::ndarray::arr1(&[1., 2.]);
+        let c = super::gemm_bias_relu(a.view(), b.view(), bias.view(), false).unwrap();
+        assert_eq!(c, arr2(&[[2., -1.], [4., -5.]]));
+        let c = super::gemm_bias_relu(a.view(), b.view(), bias.view(), true).unwrap();
+        assert_eq!(c, arr2(&[[2., 0.], [4., 0.]]));
+    }
+
+    #[test]
+    fn mismatched_product() {
+        use {ErrorKind, Matrix};
+        use ops::Op;
+        let a = Matrix::f32s(&[2, 3], &[1.0; 6]).unwrap();
+        let matmul = super::MatMul::<f32>::new(false, false);
+        let err = matmul.eval(vec![a.clone().into(), a.clone().into()]).err().unwrap();
+        match *err.kind() {
+            ErrorKind::ShapeMismatch(_, _, ref expected, ref found) => {
+                assert_eq!((expected, found), (&vec![Some(3), None], &vec![2, 3]))
+            }
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+        let bias = Matrix::f32s(&[3], &[1.0; 3]).unwrap();
+        let fused = matmul.fuse_bias_relu(true).unwrap();
+        assert!(fused.eval(vec![a.clone().into(), a.into(), bias.into()]).is_err());
+    }
+
+    #[test]
+    fn integer_division_by_zero() {
+        use {tfpb, Matrix, Model};
+        use ops::Op;
+        use tfpb::types::DataType::DT_INT32;
+        let a = Matrix::i32s(&[2], &[7, i32::min_value()]).unwrap();
+        let zero = Matrix::i32s(&[], &[0]).unwrap();
+        let minus_one = Matrix::i32s(&[], &[-1]).unwrap();
+        for b in &[zero.clone(), minus_one] {
+            let div = super::Div::<i32>::new().eval(vec![a.clone().into(), b.clone().into()]);
+            assert!(div.is_err());
+            let rem = super::Rem::<i32>::new().eval(vec![a.clone().into(), b.clone().into()]);
+            assert!(rem.is_err());
+        }
+        let two = Matrix::i32s(&[], &[2]).unwrap();
+        let div = super::Div::<i32>::new().eval(vec![a.into(), two.into()]).unwrap();
+        assert_eq!(*div[0], Matrix::i32s(&[2], &[3, i32::min_value() / 2]).unwrap());
+        let f = Matrix::f32s(&[1], &[1.0]).unwrap();
+        let f_zero = Matrix::f32s(&[], &[0.0]).unwrap();
+        let div = super::Div::<f32>::new().eval(vec![f.into(), f_zero.into()]).unwrap();
+        assert_eq!(*div[0], Matrix::f32s(&[1], &[::std::f32::INFINITY]).unwrap());
+
+        let konst = |name: &str, value: &Matrix| {
+            tfpb::node().name(name).op("Const").attr("value", value.to_pb().unwrap())
+        };
+        let graph = tfpb::graph()
+            .node(konst("a", &Matrix::i32s(&[1], &[7]).unwrap()))
+            .node(konst("zero", &zero))
+            .node(
+                tfpb::node()
+                    .name("mod")
+                    .op("FloorMod")
+                    .attr("T", DT_INT32)
+                    .input("a")
+                    .input("zero"),
+            );
+        let mut model = Model::new(graph).unwrap();
+        model.optimize(&[]).unwrap();
+        assert!(model.run_with_names(vec![], "mod").is_err());
+    }
 }
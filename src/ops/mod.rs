@@ -4,6 +4,10 @@ use std::fmt::Debug;
 use std::collections::HashMap;
 use std::sync::Arc;
 
+use ndarray::Array1;
+
+use analyser::TensorFact;
+use errors::{Error, ErrorKind};
 use {Matrix, Result};
 
 #[macro_use]
@@ -12,10 +16,12 @@ mod macros;
 mod array;
 mod math;
 mod cast;
+pub mod control_flow;
 pub mod nn;
-#[cfg(features="image_ops")]
+#[cfg(feature = "image_ops")]
 pub mod image;
 pub mod konst;
+mod logic;
 
 #[derive(Debug, Clone)]
 pub enum Input {
@@ -71,6 +77,31 @@ impl PartialEq for Input {
 
 pub trait Op: Debug + Send + Sync + 'static {
     fn eval(&self, inputs: Vec<Input>) -> Result<Vec<Input>>;
+
+    /// Infer facts about the outputs from facts about the inputs.
+    fn infer(&self, _inputs: Vec<&TensorFact>) -> Result<Vec<TensorFact>> {
+        Err("Output facts inference is not implemented for this op")?
+    }
+
+    /// Loop frame this op enters, and whether its value is constant across
+    /// iterations. Only Enter has one.
+    fn frame(&self) -> Option<(&str, bool)> {
+        None
+    }
+
+    /// Build an op computing this op followed by the addition of a bias,
+    /// and by a Relu if `relu` is set. The fused op takes the bias as an
+    /// extra last input. Ops that can not be fused return `None`.
+    fn fuse_bias_relu(&self, _relu: bool) -> Option<Box<Op>> {
+        None
+    }
+
+    /// Per-channel scale and shift this op applies to its first input, given
+    /// the values of its other inputs. Used to fold normalisations into the
+    /// weights of the preceding op.
+    fn channel_affine(&self, _params: &[&Matrix]) -> Option<(Array1<f32>, Array1<f32>)> {
+        None
+    }
 }
 
 type OpRegister = HashMap<&'static str, fn(&::tfpb::node_def::NodeDef) -> Result<Box<Op>>>;
@@ -82,29 +113,54 @@ impl OpBuilder {
         let mut reg = OpRegister::new();
         array::register_all_ops(&mut reg);
         cast::register_all_ops(&mut reg);
+        control_flow::register_all_ops(&mut reg);
+        #[cfg(feature = "image_ops")]
+        image::register_all_ops(&mut reg);
         konst::register_all_ops(&mut reg);
+        logic::register_all_ops(&mut reg);
         math::register_all_ops(&mut reg);
         nn::register_all_ops(&mut reg);
         OpBuilder(reg)
     }
 
+    /// Build the op for a node, unsupported ops and datatypes being
+    /// replaced by an `UnimplementedOp` failing when evaluated.
     pub fn build(&self, pb: &::tfpb::node_def::NodeDef) -> Result<Box<Op>> {
+        match self.try_build(pb) {
+            Err(Error(ErrorKind::UnsupportedOp(_, ref op), _)) => {
+                Ok(Box::new(UnimplementedOp(op.clone(), None, pb.to_owned())))
+            }
+            Err(Error(ErrorKind::UnsupportedDatatype(_, ref op, dt), _)) => {
+                Ok(Box::new(UnimplementedOp(op.clone(), Some(dt), pb.to_owned())))
+            }
+            built => built,
+        }
+    }
+
+    /// Build the op for a node, failing with `UnsupportedOp` or
+    /// `UnsupportedDatatype` if it can not be evaluated.
+    pub fn try_build(&self, pb: &::tfpb::node_def::NodeDef) -> Result<Box<Op>> {
         match self.0.get(pb.get_op()) {
             Some(builder) => builder(pb),
-            None => Ok(Box::new(UnimplementedOp(
+            None => Err(ErrorKind::UnsupportedOp(
+                pb.get_name().to_string(),
                 pb.get_op().to_string(),
-                pb.to_owned(),
-            ))),
+            ))?,
         }
     }
 }
 
+/// Stands for an op that can not be built, failing when evaluated with
+/// `UnsupportedDatatype` if a datatype is given, `UnsupportedOp` otherwise.
 #[derive(Debug)]
-pub struct UnimplementedOp(String, ::tfpb::node_def::NodeDef);
+pub struct UnimplementedOp(String, Option<::tfpb::types::DataType>, ::tfpb::node_def::NodeDef);
 
 impl Op for UnimplementedOp {
     fn eval(&self, _inputs: Vec<Input>) -> Result<Vec<Input>> {
-        Err(format!("unimplemented operation: {}", self.0))?
+        match self.1 {
+            Some(dt) => Err(ErrorKind::UnsupportedDatatype(String::new(), self.0.clone(), dt))?,
+            None => Err(ErrorKind::UnsupportedOp(String::new(), self.0.clone()))?,
+        }
     }
 }
 
@@ -0,0 +1,132 @@
+use ndarray::prelude::*;
+
+use analyser::TensorFact;
+use matrix::Element;
+use {Matrix, Result};
+use super::{Input, Op};
+
+/// Batch normalisation, in inference mode only: mean and variance are
+/// inputs instead of being computed over the batch.
+#[derive(Debug)]
+pub struct FusedBatchNorm {
+    epsilon: f32,
+    is_training: bool,
+    outputs: usize,
+}
+
+pub fn fused_batch_norm(pb: &::tfpb::node_def::NodeDef) -> Result<Box<Op>> {
+    if let Some(format) = pb.get_attr_opt_str("data_format")? {
+        if format != "NHWC" {
+            Err(format!("FusedBatchNorm: unsupported data format {}", format))?
+        }
+    }
+    Ok(Box::new(FusedBatchNorm {
+        epsilon: pb.get_attr_opt_float("epsilon")?.unwrap_or(0.0001),
+        is_training: pb.get_attr_opt_bool("is_training")?.unwrap_or(true),
+        outputs: if pb.get_op() == "FusedBatchNormV3" { 6 } else { 5 },
+    }))
+}
+
+impl FusedBatchNorm {
+    /// Per-channel scale and shift equivalent to the normalisation.
+    pub fn scale_shift(
+        &self,
+        scale: &Matrix,
+        offset: &Matrix,
+        mean: &Matrix,
+        variance: &Matrix,
+    ) -> Result<(Array1<f32>, Array1<f32>)> {
+        if self.is_training {
+            Err("FusedBatchNorm is only implemented for inference (is_training=false)")?
+        }
+        let vector = |m: &Matrix| -> Result<Array1<f32>> {
+            Ok(f32::mat_to_view(m)?
+                .into_dimensionality::<Ix1>()?
+                .to_owned())
+        };
+        let epsilon = self.epsilon;
+        let scale = vector(scale)? * &vector(variance)?.mapv(|v| 1.0 / (v + epsilon).sqrt());
+        let shift = vector(offset)? - &(vector(mean)? * &scale);
+        Ok((scale, shift))
+    }
+}
+
+impl Op for FusedBatchNorm {
+    fn eval(&self, inputs: Vec<Input>) -> Result<Vec<Input>> {
+        if inputs.len() != 5 {
+            Err(format!("FusedBatchNorm expects 5 inputs, got {}", inputs.len()))?
+        }
+        let (scale, shift) = self.scale_shift(&inputs[1], &inputs[2], &inputs[3], &inputs[4])?;
+        let mut data = f32::mat_to_view(&inputs[0])?.to_owned();
+        if data.shape().last() != Some(&scale.len()) {
+            Err(format!(
+                "FusedBatchNorm: {} channels, but data shape is {:?}",
+                scale.len(),
+                data.shape()
+            ))?
+        }
+        data *= &scale;
+        data += &shift;
+        let mut outputs = vec![Matrix::from(data).into()];
+        while outputs.len() < self.outputs {
+            outputs.push(inputs[3 + (outputs.len() + 1) % 2].clone());
+        }
+        Ok(outputs)
+    }
+
+    fn infer(&self, inputs: Vec<&TensorFact>) -> Result<Vec<TensorFact>> {
+        if inputs.len() != 5 {
+            Err(format!("FusedBatchNorm expects 5 inputs, got {}", inputs.len()))?
+        }
+        let mut outputs = vec![TensorFact {
+            value: None,
+            ..inputs[0].clone()
+        }];
+        while outputs.len() < self.outputs {
+            outputs.push(TensorFact {
+                value: None,
+                ..inputs[3 + (outputs.len() + 1) % 2].clone()
+            });
+        }
+        Ok(outputs)
+    }
+
+    fn channel_affine(&self, params: &[&Matrix]) -> Option<(Array1<f32>, Array1<f32>)> {
+        if params.len() != 4 {
+            return None;
+        }
+        self.scale_shift(params[0], params[1], params[2], params[3]).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inference() {
+        let bn = FusedBatchNorm {
+            epsilon: 0.0,
+            is_training: false,
+            outputs: 5,
+        };
+        let data = Matrix::f32s(&[1, 1, 2, 2], &[1.0, 2.0, 3.0, 4.0]).unwrap();
+        let scale = Matrix::f32s(&[2], &[1.0, 2.0]).unwrap();
+        let offset = Matrix::f32s(&[2], &[0.0, 1.0]).unwrap();
+        let mean = Matrix::f32s(&[2], &[1.0, 2.0]).unwrap();
+        let variance = Matrix::f32s(&[2], &[4.0, 1.0]).unwrap();
+        let outputs = bn.eval(vec![
+            data.into(),
+            scale.into(),
+            offset.into(),
+            mean.clone().into(),
+            variance.into(),
+        ]).unwrap();
+        assert_eq!(outputs.len(), 5);
+        assert_eq!(
+            *outputs[0],
+            Matrix::f32s(&[1, 1, 2, 2], &[0.0, 1.0, 1.0, 5.0]).unwrap()
+        );
+        assert_eq!(*outputs[1], mean);
+    }
+}
@@ -1,10 +1,13 @@
 use std::marker::PhantomData;
 
+use analyser::TensorFact;
+use errors::ErrorKind;
 use Result;
 use super::{Input, Op};
 use ndarray::prelude::*;
 use super::local_patch::*;
 use matrix::Datum;
+use ops::math::gemm_bias_relu;
 
 #[derive(Debug, new)]
 pub struct Conv2D<T: Datum>(LocalPatch, PhantomData<T>);
@@ -15,13 +18,27 @@ pub fn conv2d(pb: &::tfpb::node_def::NodeDef) -> Result<Box<Op>> {
     Ok(boxed_new!(Conv2D(dtype)(patch)))
 }
 
-impl<T: Datum> Op for Conv2D<T> {
-    fn eval(&self, mut inputs: Vec<Input>) -> Result<Vec<Input>> {
-        let (m_data, m_filter) = args_2!(inputs);
-        let data = T::mat_into_array(m_data.into_matrix())?;
-        let filter = T::mat_to_view(&*m_filter)?;
+impl<T: Datum> Conv2D<T> {
+    /// Convolve `data` with `filter`, adding `bias` to the output of each
+    /// image and applying a Relu if requested.
+    fn convolve(
+        &self,
+        data: Input,
+        filter: &Input,
+        bias: Option<(ArrayView1<T>, bool)>,
+    ) -> Result<ArrayD<T>> {
+        let data = T::mat_into_array(data.into_matrix())?;
+        let filter = T::mat_to_view(&*filter)?;
         let data = into_4d(data)?;
         let images = BatchImageWrapper(data.view());
+        if filter.ndim() != 4 || filter.shape()[2] != images.d() {
+            Err(ErrorKind::ShapeMismatch(
+                String::new(),
+                String::new(),
+                vec![None, None, Some(images.d()), None],
+                filter.shape().to_vec(),
+            ))?
+        }
 
         let filter_rows = filter.shape()[0];
         let filter_cols = filter.shape()[1];
@@ -38,13 +55,73 @@ impl<T: Datum> Op for Conv2D<T> {
         let mut transformed: Vec<T> = Vec::with_capacity(out_height * out_width * out_depth);
         for image in data.outer_iter() {
             let patches = self.0.mk_patches(image, (filter_rows, filter_cols))?;
-            transformed.extend(patches.dot(&filter).into_iter());
+            match bias {
+                Some((ref bias, relu)) => {
+                    let product = gemm_bias_relu(patches.view(), filter, bias.view(), relu)?;
+                    transformed.extend(product.iter().cloned())
+                }
+                None => transformed.extend(patches.dot(&filter).into_iter()),
+            }
         }
-        let transformed = Array::from_vec(transformed)
+        Ok(Array::from_vec(transformed)
             .into_shape((images.n(), out_height, out_width, out_depth))?
-            .into_dyn();
+            .into_dyn())
+    }
+
+    fn infer_shape(&self, data: &TensorFact, filter: &TensorFact) -> Result<TensorFact> {
+        let datatype = data.datatype.or(filter.datatype);
+        let input = match data.shape {
+            Some(ref shape) => shape,
+            None => return Ok(TensorFact { datatype, ..TensorFact::new() }),
+        };
+        let filter = filter.shape.clone().unwrap_or(vec![None; 4]);
+        if filter.len() != 4 {
+            Err(format!("Expected a 4D filter, got shape {:?}", filter))?
+        }
+        let shape = self.0.adjusted_shape(input, (filter[0], filter[1]), filter[3])?;
+        Ok(TensorFact::shaped(datatype, shape))
+    }
+}
+
+impl<T: Datum> Op for Conv2D<T> {
+    fn eval(&self, mut inputs: Vec<Input>) -> Result<Vec<Input>> {
+        let (m_data, m_filter) = args_2!(inputs);
+        let transformed = self.convolve(m_data, &m_filter, None)?;
         Ok(vec![T::array_into_mat(transformed).into()])
     }
+
+    fn infer(&self, mut inputs: Vec<&TensorFact>) -> Result<Vec<TensorFact>> {
+        let (data, filter) = args_2!(inputs);
+        Ok(vec![self.infer_shape(data, filter)?])
+    }
+
+    fn fuse_bias_relu(&self, relu: bool) -> Option<Box<Op>> {
+        Some(Box::new(FusedConv2D {
+            conv: Conv2D::<T>::new(self.0.clone()),
+            relu,
+        }))
+    }
+}
+
+/// Conv2D followed by a bias addition and an optional Relu.
+#[derive(Debug)]
+pub struct FusedConv2D<T: Datum> {
+    conv: Conv2D<T>,
+    relu: bool,
+}
+
+impl<T: Datum> Op for FusedConv2D<T> {
+    fn eval(&self, mut inputs: Vec<Input>) -> Result<Vec<Input>> {
+        let (m_data, m_filter, m_bias) = args_3!(inputs);
+        let bias = T::mat_to_view(&*m_bias)?.into_dimensionality::<Ix1>()?;
+        let transformed = self.conv.convolve(m_data, &m_filter, Some((bias, self.relu)))?;
+        Ok(vec![T::array_into_mat(transformed).into()])
+    }
+
+    fn infer(&self, mut inputs: Vec<&TensorFact>) -> Result<Vec<TensorFact>> {
+        let (data, filter, _) = args_3!(inputs);
+        Ok(vec![self.conv.infer_shape(data, filter)?])
+    }
 }
 
 #[cfg(test)]
@@ -162,4 +239,32 @@ mod tests {
 
         assert!(exp.close_enough(&conv.eval(vec![data.into(), filter.into()]).unwrap()[0],))
     }
+
+    #[test]
+    fn mismatched_depth() {
+        use ErrorKind;
+        let conv = Conv2D::<f32>::new(LocalPatch {
+            padding: Padding::Valid,
+            h_stride: 1,
+            v_stride: 1,
+            _data_format: DataFormat::NHWC,
+        });
+        let data = mk(&[1, 2, 2, 3]);
+        let filter = mk(&[1, 1, 2, 4]);
+        let bias = Matrix::f32s(&[4], &[0.0; 4]).unwrap();
+        let fused = conv.fuse_bias_relu(true).unwrap();
+        let errors = vec![
+            conv.eval(vec![data.clone().into(), filter.clone().into()]),
+            fused.eval(vec![data.into(), filter.into(), bias.into()]),
+        ];
+        for err in errors {
+            match *err.err().unwrap().kind() {
+                ErrorKind::ShapeMismatch(_, _, ref expected, ref found) => {
+                    assert_eq!(expected, &vec![None, None, Some(3), None]);
+                    assert_eq!(found, &vec![1, 1, 2, 4]);
+                }
+                ref kind => panic!("unexpected error {:?}", kind),
+            }
+        }
+    }
 }
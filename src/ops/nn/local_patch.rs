@@ -1,12 +1,12 @@
 use Result;
 use ndarray::prelude::*;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum DataFormat {
     NHWC,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Padding {
     Valid,
     Same,
@@ -56,7 +56,7 @@ impl<'a, T> BatchImageWrapper<'a, T> {
     }
 }
 
-#[derive(Debug, new)]
+#[derive(Clone, Debug, new)]
 pub struct LocalPatch {
     pub _data_format: DataFormat,
     pub padding: Padding,
@@ -132,6 +132,37 @@ impl LocalPatch {
         }
     }
 
+    /// Output shape for a NHWC input shape, any dimension being possibly
+    /// unknown.
+    pub fn adjusted_shape(
+        &self,
+        input: &[Option<usize>],
+        (filter_rows, filter_cols): (Option<usize>, Option<usize>),
+        depth: Option<usize>,
+    ) -> Result<Vec<Option<usize>>> {
+        if input.len() != 4 {
+            Err(format!("Expected a NHWC input, got shape {:?}", input))?
+        }
+        let dim = |size: Option<usize>, filter: Option<usize>, stride: usize| -> Result<_> {
+            match (&self.padding, size, filter) {
+                (&Padding::Same, Some(size), _) => Ok(Some((size + stride - 1) / stride)),
+                (&Padding::Valid, Some(size), Some(filter)) => {
+                    if filter > size {
+                        Err(format!("Filter of size {} larger than input {}", filter, size))?
+                    }
+                    Ok(Some((size - filter + stride) / stride))
+                }
+                _ => Ok(None),
+            }
+        };
+        Ok(vec![
+            input[0],
+            dim(input[1], filter_rows, self.v_stride)?,
+            dim(input[2], filter_cols, self.h_stride)?,
+            depth,
+        ])
+    }
+
     pub fn pad<T>(
         &self,
         data: ArrayView4<T>,
@@ -1,6 +1,9 @@
+use analyser::TensorFact;
+use matrix::Element;
 use {Matrix, Result};
 use super::{Input, Op, OpRegister};
 
+pub mod batch_norm;
 pub mod local_patch;
 pub mod conv2d;
 pub mod pools;
@@ -9,6 +12,9 @@ pub mod space_to_batch;
 pub fn register_all_ops(reg: &mut OpRegister) {
     reg.insert("AvgPool", pools::pool::<pools::AvgPooler>);
     reg.insert("Conv2D", conv2d::conv2d);
+    reg.insert("FusedBatchNorm", batch_norm::fused_batch_norm);
+    reg.insert("FusedBatchNormV2", batch_norm::fused_batch_norm);
+    reg.insert("FusedBatchNormV3", batch_norm::fused_batch_norm);
     reg.insert("MaxPool", pools::pool::<pools::MaxPooler>);
     reg.insert("Relu", Relu::build);
     reg.insert("Softmax", Softmax::build);
@@ -30,16 +36,20 @@ impl Softmax {
 impl Op for Softmax {
     fn eval(&self, mut inputs: Vec<Input>) -> Result<Vec<Input>> {
         let m_input = args_1!(inputs);
-        let mut input = m_input
-            .into_matrix()
-            .take_f32s()
-            .ok_or("Expect input #0 to be f32")?;
+        let mut input = f32::mat_into_array(m_input.into_matrix())?;
         input.map_inplace(|a| *a = a.exp());
         let norm: f32 = input.iter().sum();
         input.map_inplace(|a| *a = *a / norm);
         let result = Matrix::from(input);
         Ok(vec![result.into()])
     }
+
+    fn infer(&self, mut inputs: Vec<&TensorFact>) -> Result<Vec<TensorFact>> {
+        Ok(vec![TensorFact {
+            value: None,
+            ..args_1!(inputs).clone()
+        }])
+    }
 }
 
 pub fn arr4<A, V, U, T>(xs: &[V]) -> ::ndarray::Array4<A>
@@ -1,3 +1,6 @@
+use analyser::TensorFact;
+use matrix::Element;
+use tfpb::types::DataType;
 use {Matrix, Result};
 use super::{Input, Op};
 use ndarray::prelude::*;
@@ -26,10 +29,7 @@ pub fn pool<P: Pooler>(pb: &::tfpb::node_def::NodeDef) -> Result<Box<Op>> {
 impl<P: Pooler + ::std::fmt::Debug> Op for Pool<P> {
     fn eval(&self, mut inputs: Vec<Input>) -> Result<Vec<Input>> {
         let m_input = args_1!(inputs);
-        let data = m_input
-            .into_matrix()
-            .take_f32s()
-            .ok_or("Expected a f32 matrix")?;
+        let data = f32::mat_into_array(m_input.into_matrix())?;
         let data = into_4d(data)?;
         let images = BatchImageWrapper(data.view());
 
@@ -54,6 +54,22 @@ impl<P: Pooler + ::std::fmt::Debug> Op for Pool<P> {
 
         Ok(vec![Matrix::from(transformed.into_dyn()).into()])
     }
+
+    fn infer(&self, mut inputs: Vec<&TensorFact>) -> Result<Vec<TensorFact>> {
+        let input = args_1!(inputs);
+        let shape = match input.shape {
+            Some(ref shape) => {
+                let ksize = (Some((self.1).0), Some((self.1).1));
+                Some(self.0.adjusted_shape(shape, ksize, shape.get(3).cloned().unwrap_or(None))?)
+            }
+            None => None,
+        };
+        Ok(vec![TensorFact {
+            datatype: Some(DataType::DT_FLOAT),
+            shape,
+            value: None,
+        }])
+    }
 }
 
 #[derive(Debug)]
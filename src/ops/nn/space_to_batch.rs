@@ -1,6 +1,7 @@
 use std::marker::PhantomData;
 
 use Result;
+use tfpb::types::DataType::DT_INT32;
 use super::{Input, Op};
 use matrix::Datum;
 
@@ -19,8 +20,8 @@ pub struct SpaceToBatch<T: Datum>(PhantomData<T>);
 impl<T: Datum> Op for SpaceToBatch<T> {
     fn eval(&self, mut inputs: Vec<Input>) -> Result<Vec<Input>> {
         let (input, block_shape, paddings) = args_3!(inputs);
-        let block_shape = block_shape.as_i32s().ok_or("block shape expected as I32")?;
-        let paddings = paddings.as_i32s().ok_or("paddings expected as I32")?;
+        let block_shape = block_shape.as_i32s().ok_or_else(|| block_shape.mismatch(DT_INT32))?;
+        let paddings = paddings.as_i32s().ok_or_else(|| paddings.mismatch(DT_INT32))?;
         let mut data = T::mat_into_array(input.into_matrix())?;
 
         for (ix, pad) in paddings.outer_iter().enumerate() {
@@ -74,8 +75,8 @@ impl<T: Datum> Op for BatchToSpace<T> {
     fn eval(&self, mut inputs: Vec<Input>) -> Result<Vec<Input>> {
         use ndarray::*;
         let (input, block_shape, crops) = args_3!(inputs);
-        let block_shape = block_shape.as_i32s().ok_or("block shape expected as I32")?;
-        let crops = crops.as_i32s().ok_or("crops expected as I32")?;
+        let block_shape = block_shape.as_i32s().ok_or_else(|| block_shape.mismatch(DT_INT32))?;
+        let crops = crops.as_i32s().ok_or_else(|| crops.mismatch(DT_INT32))?;
         let data = T::mat_into_array(input.into_matrix())?;
         let input_shape = data.shape().to_vec();
         let crops = crops.clone().into_shape((block_shape.len(), 2))?;
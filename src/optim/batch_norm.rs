@@ -0,0 +1,348 @@
+//! Folding of batch normalisations into the preceding convolution.
+
+use std::sync::Arc;
+
+use ndarray::prelude::*;
+
+use ops::konst::Const;
+use {Matrix, Model, Result};
+use super::fusion::relu_after;
+
+type Edge = (usize, Option<usize>);
+
+/// Value of a constant input.
+fn const_value(model: &Model, (node, port): Edge) -> Option<Matrix> {
+    if model.nodes[node].op_name != "Const" {
+        return None;
+    }
+    let mut outputs = model.nodes[node].op.eval(vec![]).ok()?;
+    port.filter(|&p| p < outputs.len())
+        .map(|p| outputs.remove(p).into_matrix())
+}
+
+/// `value` as one coefficient per channel, if it broadcasts along the last
+/// dimension of a 4D tensor with `depth` channels.
+fn per_channel(value: &Matrix, depth: usize) -> Option<Array1<f32>> {
+    let value = value.as_f32s()?;
+    let shape = value.shape();
+    if shape.len() > 4 {
+        None
+    } else if value.len() == 1 {
+        Some(Array1::from_elem(depth, *value.iter().next()?))
+    } else if shape.last() == Some(&depth) && shape.iter().rev().skip(1).all(|&d| d == 1) {
+        Some(value.iter().cloned().collect())
+    } else {
+        None
+    }
+}
+
+/// Scale and shift applied per channel by `node` to the output of `prev`,
+/// if `node` is an affine transform of it with constant coefficients.
+fn affine_step(
+    model: &Model,
+    prev: usize,
+    node: usize,
+    depth: usize,
+) -> Option<(Array1<f32>, Array1<f32>)> {
+    let node = &model.nodes[node];
+    let data: Vec<Edge> = node.inputs.iter().filter(|i| i.1.is_some()).cloned().collect();
+    let input = (prev, Some(0));
+    if data.iter().filter(|&&i| i == input).count() != 1 {
+        return None;
+    }
+    let ones = || Array1::from_elem(depth, 1.0);
+    let zeros = || Array1::zeros(depth);
+    let other = || {
+        let other = *data.iter().find(|&&i| i != input)?;
+        per_channel(&const_value(model, other)?, depth)
+    };
+    match &*node.op_name {
+        "Mul" if data.len() == 2 => other().map(|k| (k, zeros())),
+        "Add" if data.len() == 2 => other().map(|k| (ones(), k)),
+        "BiasAdd" | "Sub" if data.len() == 2 && data[0] == input => {
+            let k = other()?;
+            if node.op_name == "Sub" {
+                Some((ones(), -k))
+            } else {
+                Some((ones(), k))
+            }
+        }
+        _ if data[0] == input => {
+            let params: Vec<Matrix> = data[1..]
+                .iter()
+                .map(|&i| const_value(model, i))
+                .collect::<Option<_>>()?;
+            let params: Vec<&Matrix> = params.iter().collect();
+            node.op
+                .channel_affine(&params)
+                .filter(|affine| affine.0.len() == depth && affine.1.len() == depth)
+        }
+        _ => None,
+    }
+}
+
+/// Fold the constant per-channel scalings and shifts following a Conv2D
+/// (`Mul`, `Add`, `Sub` and `BiasAdd` by constants, or an inference-mode
+/// `FusedBatchNorm`) into the filter and a bias.
+///
+/// The last node of the chain (or the Relu following it) is replaced by a
+/// fused convolution on the folded filter and bias, which are added to the
+/// model as new constants. Intermediate nodes are left untouched, and the
+/// chain stops at the first of the `feeds`. Returns the number of folded
+/// chains.
+pub fn fold_batch_norm(model: &mut Model, feeds: &[usize]) -> Result<usize> {
+    let successors = model.successors();
+    let mut folded = 0;
+    for conv in 0..successors.len() {
+        if model.nodes[conv].op_name != "Conv2D" {
+            continue;
+        }
+        let data: Vec<Edge> = model.nodes[conv]
+            .inputs
+            .iter()
+            .filter(|i| i.1.is_some())
+            .cloned()
+            .collect();
+        let filter = match data.get(1).and_then(|&f| const_value(model, f)) {
+            Some(Matrix::F32(ref filter)) if filter.ndim() == 4 => filter.clone(),
+            _ => continue,
+        };
+        let depth = filter.shape()[3];
+        let mut scale = Array1::from_elem(depth, 1.0f32);
+        let mut shift = Array1::zeros(depth);
+        let mut chain = vec![conv];
+        loop {
+            let last = chain[chain.len() - 1];
+            if feeds.contains(&last) {
+                break;
+            }
+            let next = match successors[last].as_slice() {
+                &[next] => next,
+                _ => break,
+            };
+            // only the first output of the last node is kept
+            if successors[next].iter().any(|&s| {
+                model.nodes[s].inputs.iter().any(|i| i.0 == next && i.1.unwrap_or(0) != 0)
+            }) {
+                break;
+            }
+            match affine_step(model, last, next, depth) {
+                Some((s, t)) => {
+                    scale *= &s;
+                    shift = shift * &s + &t;
+                    chain.push(next);
+                }
+                None => break,
+            }
+        }
+        if chain.len() == 1 {
+            continue;
+        }
+        let last = chain[chain.len() - 1];
+        let relu = relu_after(model, &successors, last).filter(|_| !feeds.contains(&last));
+        let op = match model.nodes[conv].op.fuse_bias_relu(relu.is_some()) {
+            Some(op) => op,
+            None => continue,
+        };
+        let target = relu.unwrap_or(last);
+        let name = model.nodes[target].name.clone();
+        let filter = Matrix::from(filter * &scale);
+        let filter = model.add_node(
+            format!("{}/folded_filter", name),
+            "Const",
+            Box::new(Const::new(Arc::new(filter))),
+            vec![],
+        )?;
+        let bias = model.add_node(
+            format!("{}/folded_bias", name),
+            "Const",
+            Box::new(Const::new(Arc::new(Matrix::from(shift.into_dyn())))),
+            vec![],
+        )?;
+        let mut inputs = vec![data[0], (filter, Some(0)), (bias, Some(0))];
+        for &node in chain.iter().chain(relu.iter()) {
+            inputs.extend(model.nodes[node].inputs.iter().filter(|i| i.1.is_none()));
+        }
+        let node = &mut model.nodes[target];
+        node.op = op;
+        node.op_name = "_FusedConv2D".to_string();
+        node.inputs = inputs;
+        folded += 1;
+    }
+    Ok(folded)
+}
+
+#[cfg(test)]
+mod tests {
+    use {tfpb, Matrix, Model};
+    use tfpb::types::DataType::DT_FLOAT;
+
+    fn conv() -> tfpb::graph::GraphDef {
+        tfpb::graph()
+            .node(tfpb::node().name("image").op("Placeholder"))
+            .node(
+                tfpb::node()
+                    .name("filter")
+                    .op("Const")
+                    .attr(
+                        "value",
+                        tfpb::tensor_f32(vec![1, 1, 2, 2], vec![1.0, -1.0, 2.0, -2.0]),
+                    ),
+            )
+            .node(
+                tfpb::node()
+                    .name("conv")
+                    .op("Conv2D")
+                    .attr("T", DT_FLOAT)
+                    .attr("strides", vec![1, 1, 1, 1])
+                    .attr("padding", "VALID")
+                    .input("image")
+                    .input("filter"),
+            )
+    }
+
+    fn image() -> Matrix {
+        Matrix::f32s(&[1, 1, 2, 2], &[1.0, 0.0, -1.0, 0.5]).unwrap()
+    }
+
+    #[test]
+    fn mul_add_relu() {
+        let graph = conv()
+            .node(
+                tfpb::node()
+                    .name("scale")
+                    .op("Const")
+                    .attr("value", tfpb::tensor_f32(vec![2], vec![2.0, 3.0])),
+            )
+            .node(
+                tfpb::node()
+                    .name("shift")
+                    .op("Const")
+                    .attr("value", tfpb::tensor_f32(vec![1, 1, 1, 2], vec![1.0, 1.0])),
+            )
+            .node(
+                tfpb::node()
+                    .name("mul")
+                    .op("Mul")
+                    .attr("T", DT_FLOAT)
+                    .input("conv")
+                    .input("scale"),
+            )
+            .node(
+                tfpb::node()
+                    .name("add")
+                    .op("Sub")
+                    .attr("T", DT_FLOAT)
+                    .input("mul")
+                    .input("shift"),
+            )
+            .node(tfpb::node().name("relu").op("Relu").input("add"));
+        let mut model = Model::new(graph).unwrap();
+        model.optimize(&[]).unwrap();
+        assert_eq!(model.get_node("relu").unwrap().op_name, "_FusedConv2D");
+        assert!(model.get_node("relu/folded_filter").is_ok());
+        assert_eq!(model.get_node("add").unwrap().op_name, "Sub");
+
+        let add = model.run_with_names(vec![("image", image())], "add").unwrap();
+        assert_eq!(add[0], Matrix::f32s(&[1, 1, 2, 2], &[1.0, -4.0, -1.0, -1.0]).unwrap());
+        let relu = model.run_with_names(vec![("image", image())], "relu").unwrap();
+        assert_eq!(relu[0], Matrix::f32s(&[1, 1, 2, 2], &[1.0, 0.0, 0.0, 0.0]).unwrap());
+    }
+
+    #[test]
+    fn fed_chains_are_not_folded() {
+        let graph = || {
+            conv()
+                .node(
+                    tfpb::node()
+                        .name("scale")
+                        .op("Const")
+                        .attr("value", tfpb::tensor_f32(vec![2], vec![2.0, 3.0])),
+                )
+                .node(
+                    tfpb::node()
+                        .name("mul")
+                        .op("Mul")
+                        .attr("T", DT_FLOAT)
+                        .input("conv")
+                        .input("scale"),
+                )
+                .node(tfpb::node().name("relu").op("Relu").input("mul"))
+        };
+        let value = |v: &[f32]| Matrix::f32s(&[1, 1, 2, 2], v).unwrap();
+        let cases = [
+            ("conv", value(&[0.5, -2.0, 1.0, 0.0]), value(&[1.0, 0.0, 2.0, 0.0])),
+            ("mul", value(&[1.0, -4.0, 2.0, 0.0]), value(&[1.0, 0.0, 2.0, 0.0])),
+        ];
+        for &(name, ref fed, ref expected) in &cases {
+            let model = Model::new(graph()).unwrap();
+            let relu = model.run_with_names(vec![(name, fed.clone())], "relu").unwrap();
+            assert_eq!(&relu[0], expected);
+
+            let mut model = Model::new(graph()).unwrap();
+            let id = model.node_id_by_name(name).unwrap();
+            model.optimize(&[id]).unwrap();
+            assert_eq!(model.get_node("relu").unwrap().op_name, "Relu");
+            let relu = model.run_with_names(vec![(name, fed.clone())], "relu").unwrap();
+            assert_eq!(&relu[0], expected);
+        }
+    }
+
+    #[test]
+    fn fused_batch_norm() {
+        let param = |name: &str, values: Vec<f32>| {
+            tfpb::node()
+                .name(name)
+                .op("Const")
+                .attr("value", tfpb::tensor_f32(vec![2], values))
+        };
+        let graph = conv()
+            .node(param("gamma", vec![2.0, 1.0]))
+            .node(param("beta", vec![0.0, 1.0]))
+            .node(param("mean", vec![1.0, 0.0]))
+            .node(param("variance", vec![4.0, 1.0]))
+            .node(
+                tfpb::node()
+                    .name("bn")
+                    .op("FusedBatchNorm")
+                    .attr("T", DT_FLOAT)
+                    .attr("epsilon", 0.0f32)
+                    .attr("is_training", false)
+                    .input("conv")
+                    .input("gamma")
+                    .input("beta")
+                    .input("mean")
+                    .input("variance"),
+            );
+        let mut model = Model::new(graph).unwrap();
+        model.optimize(&[]).unwrap();
+        assert_eq!(model.get_node("bn").unwrap().op_name, "_FusedConv2D");
+
+        // conv is [1, -1, 0, 0]
+        let bn = model.run_with_names(vec![("image", image())], "bn").unwrap();
+        let expected = Matrix::f32s(&[1, 1, 2, 2], &[0.0, 0.0, -1.0, 1.0]).unwrap();
+        assert!(bn[0].close_enough(&expected));
+    }
+
+    #[test]
+    fn spatial_scale_is_not_folded() {
+        let graph = conv()
+            .node(
+                tfpb::node()
+                    .name("scale")
+                    .op("Const")
+                    .attr("value", tfpb::tensor_f32(vec![1, 1, 2, 1], vec![2.0, 3.0])),
+            )
+            .node(
+                tfpb::node()
+                    .name("mul")
+                    .op("Mul")
+                    .attr("T", DT_FLOAT)
+                    .input("conv")
+                    .input("scale"),
+            );
+        let mut model = Model::new(graph).unwrap();
+        model.optimize(&[]).unwrap();
+        assert_eq!(model.get_node("mul").unwrap().op_name, "Mul");
+    }
+}
@@ -0,0 +1,157 @@
+//! Constant folding.
+
+use std::sync::Arc;
+
+use ops::Input;
+use ops::konst::Const;
+use {Model, Node, Result};
+
+/// Can the node be evaluated once and for all if its inputs are constant ?
+fn foldable(node: &Node) -> bool {
+    !node.inputs.is_empty() && node.op_name != "Placeholder"
+        && !::ops::control_flow::CONTROL_FLOW_OPS.contains(&&*node.op_name)
+}
+
+/// Evaluate the nodes depending only on constants, and replace them by
+/// constants holding the result.
+///
+/// Nodes failing to evaluate, or having several outputs, are left as they
+/// are. So are the `feeds`, and the nodes depending on them, as their value
+/// will be given at run time. Returns the number of folded nodes.
+pub fn fold_constants(model: &mut Model, feeds: &[usize]) -> Result<usize> {
+    let mut values: Vec<Option<Vec<Input>>> = vec![None; model.nodes.len()];
+    let mut rejected = ::bit_set::BitSet::with_capacity(model.nodes.len());
+    rejected.extend(feeds.iter().cloned());
+    let mut folded = 0;
+    loop {
+        let mut done_something = false;
+        for id in 0..model.nodes.len() {
+            if values[id].is_some() || rejected.contains(id) {
+                continue;
+            }
+            let outputs = {
+                let node = &model.nodes[id];
+                if node.op_name == "Const" {
+                    node.eval(vec![])?
+                } else if foldable(node) && node.inputs.iter().all(|i| values[i.0].is_some()) {
+                    let inputs: Option<Vec<Input>> = node.inputs
+                        .iter()
+                        .filter_map(|&(prec, port)| {
+                            port.map(|port| values[prec].as_ref().and_then(|v| v.get(port).cloned()))
+                        })
+                        .collect();
+                    match inputs.map(|inputs| node.eval(inputs)) {
+                        Some(Ok(ref outputs)) if outputs.len() == 1 => outputs.clone(),
+                        _ => {
+                            rejected.insert(id);
+                            continue;
+                        }
+                    }
+                } else {
+                    continue;
+                }
+            };
+            if model.nodes[id].op_name != "Const" {
+                let value = match outputs[0] {
+                    Input::Shared(ref value) => value.clone(),
+                    Input::Owned(ref value) => Arc::new(value.clone()),
+                };
+                let node = &mut model.nodes[id];
+                node.op = Box::new(Const::new(value));
+                node.op_name = "Const".to_string();
+                node.inputs.clear();
+                folded += 1;
+            }
+            values[id] = Some(outputs);
+            done_something = true;
+        }
+        if !done_something {
+            break;
+        }
+    }
+    Ok(folded)
+}
+
+#[cfg(test)]
+mod tests {
+    use {tfpb, Matrix, Model};
+    use tfpb::types::DataType::DT_FLOAT;
+
+    #[test]
+    fn fold_batch_norm_scale() {
+        let graph = tfpb::graph()
+            .node(tfpb::node().name("input").op("Placeholder"))
+            .node(
+                tfpb::node()
+                    .name("var")
+                    .op("Const")
+                    .attr("value", tfpb::tensor_f32(vec![2], vec![4.0, 16.0])),
+            )
+            .node(
+                tfpb::node()
+                    .name("eps")
+                    .op("Const")
+                    .attr("value", tfpb::tensor_f32(vec![], vec![0.0])),
+            )
+            .node(
+                tfpb::node()
+                    .name("add")
+                    .op("Add")
+                    .attr("T", DT_FLOAT)
+                    .input("var")
+                    .input("eps"),
+            )
+            .node(tfpb::node().name("rsqrt").op("Rsqrt").input("add"))
+            .node(
+                tfpb::node()
+                    .name("mul")
+                    .op("Mul")
+                    .attr("T", DT_FLOAT)
+                    .input("input")
+                    .input("rsqrt"),
+            );
+        let mut model = Model::new(graph).unwrap();
+        assert_eq!(model.get_node("rsqrt").unwrap().op_name, "Rsqrt");
+        model.optimize(&[]).unwrap();
+        for name in &["add", "rsqrt"] {
+            let node = model.get_node(name).unwrap();
+            assert_eq!(node.op_name, "Const");
+            assert!(node.inputs.is_empty());
+        }
+        assert_eq!(model.get_node("mul").unwrap().op_name, "Mul");
+        let plan = model.plan_for_one(model.node_id_by_name("mul").unwrap()).unwrap();
+        assert_eq!(plan.order().len(), 3);
+
+        let input = Matrix::f32s(&[2], &[1.0, 1.0]).unwrap();
+        let result = model.run_with_names(vec![("input", input)], "mul").unwrap();
+        assert_eq!(result[0], Matrix::f32s(&[2], &[0.5, 0.25]).unwrap());
+    }
+
+    #[test]
+    fn placeholders_are_not_folded() {
+        let mut model = ::for_path("tests/plus3.pb").unwrap();
+        model.optimize(&[]).unwrap();
+        assert_eq!(model.get_node("output").unwrap().op_name, "Add");
+    }
+
+    #[test]
+    fn feeds_are_not_folded() {
+        let graph = tfpb::graph()
+            .node(
+                tfpb::node()
+                    .name("a")
+                    .op("Const")
+                    .attr("value", tfpb::tensor_f32(vec![1], vec![1.0])),
+            )
+            .node(tfpb::node().name("b").op("Abs").input("a"))
+            .node(tfpb::node().name("c").op("Abs").input("b"));
+        let mut model = Model::new(graph).unwrap();
+        let b = model.node_id_by_name("b").unwrap();
+        model.optimize(&[b]).unwrap();
+        assert_eq!(model.get_node("c").unwrap().op_name, "Abs");
+
+        let fed = Matrix::f32s(&[1], &[-2.0]).unwrap();
+        let result = model.run_with_names(vec![("b", fed)], "c").unwrap();
+        assert_eq!(result[0], Matrix::f32s(&[1], &[2.0]).unwrap());
+    }
+}
@@ -0,0 +1,288 @@
+//! Fusion of convolutions and matrix products with the bias addition and
+//! the Relu following them.
+
+use analyser::TensorFact;
+use {Model, Result};
+
+/// Ops able to apply a bias and a Relu while computing their output.
+const FUSABLE: &[&str] = &["Conv2D", "MatMul"];
+
+type Edge = (usize, Option<usize>);
+
+/// What is known about an edge without analysing the whole model: the
+/// datatype and shape of constants, and the attributes of placeholders.
+fn shallow_fact(model: &Model, (node, port): Edge) -> TensorFact {
+    let node = &model.nodes[node];
+    let port = port.unwrap_or(0);
+    if node.op_name == "Const" {
+        if let Ok(outputs) = node.op.eval(vec![]) {
+            if let Some(value) = outputs.get(port) {
+                let shape = value.shape().iter().map(|&d| Some(d)).collect();
+                return TensorFact::shaped(Some(value.datatype()), shape);
+            }
+        }
+    } else if node.op_name == "Placeholder" {
+        if let Ok(mut facts) = node.op.infer(vec![]) {
+            if port < facts.len() {
+                return facts.remove(port);
+            }
+        }
+    }
+    TensorFact::new()
+}
+
+/// If `node` adds a bias to the output of a fusable op, return the id of
+/// this op and the bias input.
+fn bias_add(model: &Model, node: usize) -> Option<(usize, Edge)> {
+    let node = &model.nodes[node];
+    let data: Vec<_> = node.inputs.iter().filter(|i| i.1.is_some()).cloned().collect();
+    if data.len() != 2 || data[0].0 == data[1].0 {
+        return None;
+    }
+    let fusable = |i: Edge| i.1 == Some(0) && FUSABLE.contains(&&*model.nodes[i.0].op_name);
+    let (producer, bias) = match &*node.op_name {
+        "BiasAdd" if fusable(data[0]) => (data[0], data[1]),
+        "Add" if fusable(data[0]) => (data[0], data[1]),
+        "Add" if fusable(data[1]) => (data[1], data[0]),
+        _ => return None,
+    };
+    let bias_fact = shallow_fact(model, bias);
+    if node.op_name == "BiasAdd" {
+        if bias_fact.rank().map(|r| r != 1).unwrap_or(false) {
+            return None;
+        }
+    } else {
+        // Add broadcasts any shape, make sure the bias is a vector along
+        // the last dimension
+        let producer_node = &model.nodes[producer.0];
+        let inputs: Vec<TensorFact> = producer_node
+            .inputs
+            .iter()
+            .filter(|i| i.1.is_some())
+            .map(|&i| shallow_fact(model, i))
+            .collect();
+        let depth = producer_node
+            .op
+            .infer(inputs.iter().collect())
+            .ok()
+            .and_then(|facts| facts.into_iter().next())
+            .and_then(|fact| fact.shape)
+            .and_then(|s| s.last().cloned())
+            .and_then(|d| d);
+        match bias_fact.shape {
+            Some(ref s) if s.len() == 1 && s[0].is_some() && s[0] == depth => (),
+            _ => return None,
+        }
+    }
+    Some((producer.0, bias))
+}
+
+/// The Relu consuming `node`, if it is its only successor.
+pub fn relu_after(model: &Model, successors: &[Vec<usize>], node: usize) -> Option<usize> {
+    match successors[node].as_slice() {
+        &[next] if model.nodes[next].op_name == "Relu"
+            && model.nodes[next].inputs == [(node, Some(0))] =>
+        {
+            Some(next)
+        }
+        _ => None,
+    }
+}
+
+/// Replace `Conv2D -> BiasAdd -> Relu` and `MatMul -> Add -> Relu` chains
+/// (the Relu being optional) by a single fused op.
+///
+/// The fused op takes the place of the last node of the chain, the bias and
+/// activation being applied while the output of the product is written. The
+/// intermediate nodes are left untouched, so they can still be fetched.
+/// Chains whose intermediate nodes are among the `feeds` are not fused.
+/// Returns the number of fused chains.
+pub fn fuse_bias_relu(model: &mut Model, feeds: &[usize]) -> Result<usize> {
+    let successors = model.successors();
+    let mut fused = 0;
+    for id in 0..model.nodes.len() {
+        let (producer, bias) = match bias_add(model, id) {
+            Some(found) => found,
+            None => continue,
+        };
+        if successors[producer] != [id] || feeds.contains(&producer) {
+            continue;
+        }
+        let relu = relu_after(model, &successors, id);
+        if relu.is_some() && feeds.contains(&id) {
+            continue;
+        }
+        let op = match model.nodes[producer].op.fuse_bias_relu(relu.is_some()) {
+            Some(op) => op,
+            None => continue,
+        };
+        let mut inputs: Vec<_> = model.nodes[producer]
+            .inputs
+            .iter()
+            .filter(|i| i.1.is_some())
+            .cloned()
+            .collect();
+        inputs.push(bias);
+        for &node in &[producer, id] {
+            inputs.extend(model.nodes[node].inputs.iter().filter(|i| i.1.is_none()));
+        }
+        let op_name = format!("_Fused{}", model.nodes[producer].op_name);
+        let node = &mut model.nodes[relu.unwrap_or(id)];
+        node.op = op;
+        node.op_name = op_name;
+        node.inputs = inputs;
+        fused += 1;
+    }
+    Ok(fused)
+}
+
+#[cfg(test)]
+mod tests {
+    use {tfpb, Matrix, Model};
+    use tfpb::types::DataType::DT_FLOAT;
+
+    fn conv_bias_relu_graph() -> tfpb::graph::GraphDef {
+        tfpb::graph()
+            .node(tfpb::node().name("image").op("Placeholder"))
+            .node(
+                tfpb::node()
+                    .name("filter")
+                    .op("Const")
+                    .attr(
+                        "value",
+                        tfpb::tensor_f32(vec![1, 1, 2, 2], vec![1.0, -1.0, 2.0, -2.0]),
+                    ),
+            )
+            .node(tfpb::node().name("bias").op("Placeholder"))
+            .node(
+                tfpb::node()
+                    .name("conv")
+                    .op("Conv2D")
+                    .attr("T", DT_FLOAT)
+                    .attr("strides", vec![1, 1, 1, 1])
+                    .attr("padding", "VALID")
+                    .input("image")
+                    .input("filter"),
+            )
+            .node(
+                tfpb::node()
+                    .name("biased")
+                    .op("BiasAdd")
+                    .attr("T", DT_FLOAT)
+                    .input("conv")
+                    .input("bias"),
+            )
+            .node(tfpb::node().name("relu").op("Relu").input("biased"))
+    }
+
+    #[test]
+    fn conv_bias_relu() {
+        let mut model = Model::new(conv_bias_relu_graph()).unwrap();
+        model.optimize(&[]).unwrap();
+        assert_eq!(model.get_node("relu").unwrap().op_name, "_FusedConv2D");
+        assert_eq!(model.get_node("biased").unwrap().op_name, "BiasAdd");
+
+        let image = Matrix::f32s(&[1, 1, 2, 2], &[1.0, 0.0, -1.0, 0.0]).unwrap();
+        let bias = Matrix::f32s(&[2], &[1.0, -0.5]).unwrap();
+        let conv = model.run_with_names(vec![("image", image.clone())], "conv").unwrap();
+        assert_eq!(conv[0], Matrix::f32s(&[1, 1, 2, 2], &[1.0, -1.0, -1.0, 1.0]).unwrap());
+        let relu = model
+            .run_with_names(vec![("image", image), ("bias", bias)], "relu")
+            .unwrap();
+        assert_eq!(relu[0], Matrix::f32s(&[1, 1, 2, 2], &[2.0, 0.0, 0.0, 0.5]).unwrap());
+    }
+
+    #[test]
+    fn fed_chains_are_not_fused() {
+        let biased = Matrix::f32s(&[1, 1, 2, 2], &[1.0, -1.0, -2.0, 2.0]).unwrap();
+        let expected = Matrix::f32s(&[1, 1, 2, 2], &[1.0, 0.0, 0.0, 2.0]).unwrap();
+
+        let model = Model::new(conv_bias_relu_graph()).unwrap();
+        let relu = model.run_with_names(vec![("biased", biased.clone())], "relu").unwrap();
+        assert_eq!(relu[0], expected);
+
+        let mut model = Model::new(conv_bias_relu_graph()).unwrap();
+        let fed = model.node_id_by_name("biased").unwrap();
+        model.optimize(&[fed]).unwrap();
+        assert_eq!(model.get_node("relu").unwrap().op_name, "Relu");
+        let relu = model.run_with_names(vec![("biased", biased)], "relu").unwrap();
+        assert_eq!(relu[0], expected);
+    }
+
+    #[test]
+    fn matmul_add() {
+        let graph = tfpb::graph()
+            .node(tfpb::node().name("a").op("Placeholder"))
+            .node(
+                tfpb::node()
+                    .name("b")
+                    .op("Const")
+                    .attr(
+                        "value",
+                        tfpb::tensor_f32(vec![2, 3], vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]),
+                    ),
+            )
+            .node(
+                tfpb::node()
+                    .name("bias")
+                    .op("Const")
+                    .attr("value", tfpb::tensor_f32(vec![3], vec![-1.0, -2.0, -3.0])),
+            )
+            .node(
+                tfpb::node()
+                    .name("product")
+                    .op("MatMul")
+                    .attr("T", DT_FLOAT)
+                    .attr("transpose_a", true)
+                    .input("a")
+                    .input("b"),
+            )
+            .node(
+                tfpb::node()
+                    .name("add")
+                    .op("Add")
+                    .attr("T", DT_FLOAT)
+                    .input("bias")
+                    .input("product"),
+            );
+        let mut model = Model::new(graph).unwrap();
+        model.optimize(&[]).unwrap();
+        assert_eq!(model.get_node("add").unwrap().op_name, "_FusedMatMul");
+
+        let a = Matrix::f32s(&[2, 1], &[1.0, 1.0]).unwrap();
+        let result = model.run_with_names(vec![("a", a)], "add").unwrap();
+        assert_eq!(result[0], Matrix::f32s(&[1, 3], &[4.0, 5.0, 6.0]).unwrap());
+    }
+
+    #[test]
+    fn broadcasting_add_is_not_fused() {
+        let graph = tfpb::graph()
+            .node(tfpb::node().name("a").op("Placeholder"))
+            .node(tfpb::node().name("b").op("Placeholder"))
+            .node(
+                tfpb::node()
+                    .name("bias")
+                    .op("Const")
+                    .attr("value", tfpb::tensor_f32(vec![1, 1], vec![1.0])),
+            )
+            .node(
+                tfpb::node()
+                    .name("product")
+                    .op("MatMul")
+                    .attr("T", DT_FLOAT)
+                    .input("a")
+                    .input("b"),
+            )
+            .node(
+                tfpb::node()
+                    .name("add")
+                    .op("Add")
+                    .attr("T", DT_FLOAT)
+                    .input("product")
+                    .input("bias"),
+            );
+        let mut model = Model::new(graph).unwrap();
+        model.optimize(&[]).unwrap();
+        assert_eq!(model.get_node("add").unwrap().op_name, "Add");
+    }
+}
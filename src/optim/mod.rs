@@ -0,0 +1,15 @@
+//! Model optimisations.
+//!
+//! Passes rewrite the nodes of a loaded `Model` in place, and are run on
+//! demand by `Model::optimize`. Node ids and names are preserved, so plans
+//! and fetches work the same on the optimised model. Nodes inside rewritten
+//! chains are bypassed, so only the nodes passed as feeds to the passes can
+//! be fed.
+
+mod batch_norm;
+mod constants;
+mod fusion;
+
+pub use self::batch_norm::fold_batch_norm;
+pub use self::constants::fold_constants;
+pub use self::fusion::fuse_bias_relu;
@@ -0,0 +1,226 @@
+//! Multi-threaded plan execution.
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use {Error, ModelState, Plan, Result};
+use ops::Input;
+
+impl Plan {
+    /// Run the plan, evaluating independent nodes concurrently.
+    ///
+    /// Ready nodes are dispatched to a pool of `threads` workers, while
+    /// the calling thread gathers inputs and stores results. Values are
+    /// freed the same way `Plan::run` does, and the results are identical.
+    ///
+    /// An op panicking in a worker makes the run fail with an error.
+    ///
+    /// Plans for models with control flow are run sequentially.
+    pub fn run_parallel(&self, state: &mut ModelState, threads: usize) -> Result<()> {
+        if self.dataflow.is_some() {
+            return self.run(state);
+        }
+        let model = state.model.clone();
+        let model = &*model;
+        let mut in_plan = vec![None; model.nodes.len()];
+        for (ix, step) in self.steps.iter().enumerate() {
+            in_plan[step.node] = Some(ix);
+        }
+        // precursors to wait for, and successors to notify, for each step,
+        // and how many times each value will be consumed
+        let mut pending = vec![0usize; self.steps.len()];
+        let mut successors: Vec<Vec<usize>> = vec![vec![]; self.steps.len()];
+        let mut uses = vec![0usize; model.nodes.len()];
+        for (ix, step) in self.steps.iter().enumerate() {
+            for i in &model.nodes[step.node].inputs {
+                if let Some(prec) = in_plan[i.0] {
+                    pending[ix] += 1;
+                    successors[prec].push(ix);
+                }
+            }
+            for &(prec, _) in &step.inputs {
+                uses[prec] += 1;
+            }
+        }
+
+        let (job_tx, job_rx) = mpsc::channel::<(usize, Vec<Input>)>();
+        let job_rx = Mutex::new(job_rx);
+        let (result_tx, result_rx) = mpsc::channel::<(usize, Result<Vec<Input>>, Duration)>();
+
+        thread::scope(|scope| {
+            for _ in 0..threads.max(1) {
+                let job_rx = &job_rx;
+                let result_tx = result_tx.clone();
+                scope.spawn(move || loop {
+                    let job = job_rx.lock().unwrap().recv();
+                    match job {
+                        Ok((ix, inputs)) => {
+                            let start = Instant::now();
+                            let node = &model.nodes[self.steps[ix].node];
+                            let eval = AssertUnwindSafe(|| node.eval(inputs));
+                            let outputs = panic::catch_unwind(eval).unwrap_or_else(|_| {
+                                Err(Error::from("Op panicked").in_node(&node.name, &node.op_name))
+                            });
+                            if result_tx.send((ix, outputs, start.elapsed())).is_err() {
+                                break;
+                            }
+                        }
+                        Err(_) => break,
+                    }
+                });
+            }
+            // only workers hold senders, so that the scheduler notices if
+            // they all went away
+            drop(result_tx);
+
+            let mut schedule = || -> Result<()> {
+                let mut ready: Vec<usize> = (0..self.steps.len())
+                    .filter(|&ix| pending[ix] == 0)
+                    .collect();
+                let mut running = 0;
+                loop {
+                    while let Some(ix) = ready.pop() {
+                        let step = &self.steps[ix];
+                        if state.outputs[step.node].is_some() {
+                            self.done(state, &mut uses, &mut pending, &successors, &mut ready, ix);
+                            continue;
+                        }
+                        let inputs = state.step_inputs(step)?;
+                        state.before_eval(step.node, &inputs);
+                        job_tx
+                            .send((ix, inputs))
+                            .map_err(|_| "Parallel executor worker pool went away")?;
+                        running += 1;
+                    }
+                    if running == 0 {
+                        return Ok(());
+                    }
+                    let (ix, outputs, duration) = result_rx
+                        .recv()
+                        .map_err(|_| "Parallel executor worker pool went away")?;
+                    running -= 1;
+                    state.store(self.steps[ix].node, outputs?, duration);
+                    self.done(state, &mut uses, &mut pending, &successors, &mut ready, ix);
+                }
+            };
+            let result = schedule();
+            drop(job_tx);
+            result
+        })
+    }
+
+    fn done(
+        &self,
+        state: &mut ModelState,
+        uses: &mut [usize],
+        pending: &mut [usize],
+        successors: &[Vec<usize>],
+        ready: &mut Vec<usize>,
+        ix: usize,
+    ) {
+        for &(prec, _) in &self.steps[ix].inputs {
+            uses[prec] -= 1;
+            if uses[prec] == 0 && !self.targets.contains(&prec) && state.can_free(prec) {
+                state.outputs[prec] = None;
+            }
+        }
+        for &s in &successors[ix] {
+            pending[s] -= 1;
+            if pending[s] == 0 {
+                ready.push(s);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {tfpb, ErrorKind, Matrix, Model, Result};
+    use ops::{Input, Op};
+    use tfpb::types::DataType::DT_FLOAT;
+
+    fn branchy() -> Model {
+        let bin = |name: &str, op: &str, a: &str, b: &str| {
+            tfpb::node()
+                .name(name)
+                .op(op)
+                .attr("T", DT_FLOAT)
+                .input(a)
+                .input(b)
+        };
+        let graph = tfpb::graph()
+            .node(tfpb::node().name("input").op("Placeholder"))
+            .node(
+                tfpb::node()
+                    .name("two")
+                    .op("Const")
+                    .attr("value", tfpb::tensor_f32(vec![1], vec![2.0])),
+            )
+            .node(bin("a", "Add", "input", "two"))
+            .node(bin("b", "Mul", "input", "two"))
+            .node(bin("c", "Sub", "input", "two"))
+            .node(bin("d", "Div", "input", "two"))
+            .node(bin("ab", "Mul", "a", "b"))
+            .node(bin("cd", "Add", "c", "d"))
+            .node(bin("output", "Sub", "ab", "cd"));
+        Model::new(graph).unwrap()
+    }
+
+    #[test]
+    fn same_as_sequential() {
+        let model = branchy();
+        let input = model.node_id_by_name("input").unwrap();
+        let output = model.node_id_by_name("output").unwrap();
+        let value = Matrix::f32s(&[3], &[1.0, 2.0, 3.0]).unwrap();
+        let expected = model.run(vec![(input, value.clone())], output).unwrap();
+
+        let plan = model.plan_for(&[output]).unwrap();
+        for threads in 1..5 {
+            let mut state = model.state();
+            state.set_value(input, value.clone()).unwrap();
+            plan.run_parallel(&mut state, threads).unwrap();
+            let intermediates = state.outputs.iter().filter(|o| o.is_some()).count();
+            assert_eq!(intermediates, 1);
+            assert_eq!(state.take(output).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn errors_are_reported() {
+        let model = branchy();
+        let output = model.node_id_by_name("output").unwrap();
+        let input = model.node_id_by_name("input").unwrap();
+        let plan = model.plan_for(&[output]).unwrap();
+        let mut state = model.state();
+        state.set_value(input, Matrix::i32s(&[1], &[1]).unwrap()).unwrap();
+        assert!(plan.run_parallel(&mut state, 2).is_err());
+    }
+
+    #[test]
+    fn panics_are_reported() {
+        #[derive(Debug)]
+        struct Panic;
+        impl Op for Panic {
+            fn eval(&self, _inputs: Vec<Input>) -> Result<Vec<Input>> {
+                panic!("boom")
+            }
+        }
+        let mut model = branchy();
+        let output = model.node_id_by_name("output").unwrap();
+        let input = model.node_id_by_name("input").unwrap();
+        let cd = model.node_id_by_name("cd").unwrap();
+        model.nodes[cd].op = Box::new(Panic);
+        let plan = model.plan_for(&[output]).unwrap();
+        for threads in 1..4 {
+            let mut state = model.state();
+            state.set_value(input, Matrix::f32s(&[1], &[1.0]).unwrap()).unwrap();
+            let err = plan.run_parallel(&mut state, threads).err().unwrap();
+            match *err.kind() {
+                ErrorKind::EvalFailed(ref node, _) => assert_eq!(node, "cd"),
+                ref e => panic!("unexpected error {:?}", e),
+            }
+        }
+    }
+}
@@ -0,0 +1,222 @@
+//! Per-node profiling of model evaluation.
+//!
+//! Profiling is switched on with `ModelState::set_profiling`. Each evaluated
+//! node is then recorded with the wall time spent in its op and the shapes
+//! of its outputs.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+use Node;
+use ops::Input;
+
+/// Evaluation record of a single node.
+#[derive(Clone, Debug)]
+pub struct NodeProfile {
+    pub node: usize,
+    pub name: String,
+    pub op_name: String,
+    pub duration: Duration,
+    pub shapes: Vec<Vec<usize>>,
+}
+
+/// Time spent in all the nodes of a given op.
+#[derive(Clone, Debug)]
+pub struct OpProfile {
+    pub op_name: String,
+    pub count: usize,
+    pub duration: Duration,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Profile {
+    pub nodes: Vec<NodeProfile>,
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1e3 + d.subsec_nanos() as f64 / 1e6
+}
+
+fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains(&[',', '"', '\n'][..]) {
+        format!("\"{}\"", s.replace("\"", "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+impl Profile {
+    pub fn record(&mut self, node: &Node, duration: Duration, outputs: &[Input]) {
+        self.nodes.push(NodeProfile {
+            node: node.id,
+            name: node.name.clone(),
+            op_name: node.op_name.clone(),
+            duration,
+            shapes: outputs.iter().map(|o| o.shape().to_vec()).collect(),
+        })
+    }
+
+    pub fn clear(&mut self) {
+        self.nodes.clear()
+    }
+
+    /// Total time spent in ops.
+    pub fn total(&self) -> Duration {
+        self.nodes.iter().map(|n| n.duration).sum()
+    }
+
+    /// Node records, slowest first.
+    pub fn by_node(&self) -> Vec<&NodeProfile> {
+        let mut nodes: Vec<&NodeProfile> = self.nodes.iter().collect();
+        nodes.sort_by_key(|n| ::std::cmp::Reverse(n.duration));
+        nodes
+    }
+
+    /// Time aggregated by op, slowest first.
+    pub fn by_op(&self) -> Vec<OpProfile> {
+        let mut ops: HashMap<&str, OpProfile> = HashMap::new();
+        for node in &self.nodes {
+            let op = ops.entry(&*node.op_name).or_insert_with(|| OpProfile {
+                op_name: node.op_name.clone(),
+                count: 0,
+                duration: Duration::default(),
+            });
+            op.count += 1;
+            op.duration += node.duration;
+        }
+        let mut ops: Vec<OpProfile> = ops.into_iter().map(|(_, v)| v).collect();
+        ops.sort_by(|a, b| b.duration.cmp(&a.duration).then(a.op_name.cmp(&b.op_name)));
+        ops
+    }
+
+    /// One line per node, slowest first.
+    pub fn to_csv(&self) -> String {
+        let mut csv = "node,name,op,time_ms,shapes\n".to_string();
+        for n in self.by_node() {
+            csv.push_str(&format!(
+                "{},{},{},{:.6},{}\n",
+                n.node,
+                csv_field(&n.name),
+                csv_field(&n.op_name),
+                millis(n.duration),
+                csv_field(&format!("{:?}", n.shapes))
+            ));
+        }
+        csv
+    }
+
+    /// Node records and op aggregates, as a JSON document.
+    pub fn to_json(&self) -> String {
+        let nodes: Vec<String> = self.by_node()
+            .iter()
+            .map(|n| {
+                format!(
+                    "{{\"node\":{},\"name\":{},\"op\":{},\"time_ms\":{:.6},\"shapes\":{:?}}}",
+                    n.node,
+                    json_string(&n.name),
+                    json_string(&n.op_name),
+                    millis(n.duration),
+                    n.shapes
+                )
+            })
+            .collect();
+        let ops: Vec<String> = self.by_op()
+            .iter()
+            .map(|o| {
+                format!(
+                    "{{\"op\":{},\"count\":{},\"time_ms\":{:.6}}}",
+                    json_string(&o.op_name),
+                    o.count,
+                    millis(o.duration)
+                )
+            })
+            .collect();
+        format!(
+            "{{\"total_ms\":{:.6},\"ops\":[{}],\"nodes\":[{}]}}",
+            millis(self.total()),
+            ops.join(","),
+            nodes.join(",")
+        )
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let total = millis(self.total());
+        writeln!(f, "Total: {:.3} ms over {} nodes", total, self.nodes.len())?;
+        writeln!(f, "By op:")?;
+        for op in self.by_op() {
+            let ms = millis(op.duration);
+            writeln!(
+                f,
+                "  {:>10.3} ms {:>5.1}% {:>5} x {}",
+                ms,
+                if total > 0.0 { 100.0 * ms / total } else { 0.0 },
+                op.count,
+                op.op_name
+            )?;
+        }
+        writeln!(f, "By node:")?;
+        for n in self.by_node() {
+            writeln!(
+                f,
+                "  {:>10.3} ms {} ({}) {:?}",
+                millis(n.duration),
+                n.name,
+                n.op_name,
+                n.shapes
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use Matrix;
+
+    #[test]
+    fn profile_run() {
+        let model = ::for_path("tests/plus3.pb").unwrap();
+        let mut state = model.state();
+        state.set_profiling(true);
+        let input = Matrix::f32s(&[3], &[1.0, 2.0, 3.0]).unwrap();
+        state.run(vec![(0, input)], 2).unwrap();
+        let profile = state.profile().unwrap();
+        assert_eq!(profile.nodes.len(), 2);
+        assert_eq!(profile.by_op().len(), 2);
+        let add = profile.nodes.iter().find(|n| n.op_name == "Add").unwrap();
+        assert_eq!(add.name, "output");
+        assert_eq!(add.shapes, vec![vec![3]]);
+        let csv = profile.to_csv();
+        assert_eq!(csv.lines().count(), 3);
+        assert!(csv.contains(",output,Add,"));
+        let json = profile.to_json();
+        assert!(json.starts_with("{\"total_ms\":"));
+        assert!(json.contains("\"name\":\"output\",\"op\":\"Add\""));
+        assert!(json.contains("\"shapes\":[[3]]"));
+        assert!(format!("{}", profile).contains("output (Add) [[3]]"));
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(super::json_string("a\"b\\c\n"), "\"a\\\"b\\\\c\\u000a\"");
+        assert_eq!(super::csv_field("a,b"), "\"a,b\"");
+    }
+}
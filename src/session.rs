@@ -0,0 +1,176 @@
+//! Owned evaluation states, for sharing a Model between threads.
+//!
+//! A `ModelState` borrows its `Model`, which ties it to a stack lifetime. A
+//! `Session` holds an `Arc<Model>` instead, so it can be stored in structs
+//! or moved to another thread. A `SessionPool` keeps a handful of idle
+//! sessions around for request-handling threads to pick from.
+
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+
+use {Model, ModelRef, ModelState};
+
+/// An evaluation state owning a shared reference to its Model.
+pub type Session = ModelState<'static>;
+
+impl ModelState<'static> {
+    pub fn new(model: Arc<Model>) -> Session {
+        ModelState::with_model(ModelRef::Shared(model))
+    }
+}
+
+/// A pool of reusable sessions over the same Model.
+pub struct SessionPool {
+    model: Arc<Model>,
+    idle: Mutex<Vec<Session>>,
+    capacity: usize,
+}
+
+impl SessionPool {
+    /// Create a pool keeping at most `capacity` idle sessions.
+    pub fn new(model: Arc<Model>, capacity: usize) -> SessionPool {
+        SessionPool {
+            model,
+            idle: Mutex::new(Vec::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    pub fn model(&self) -> &Arc<Model> {
+        &self.model
+    }
+
+    /// Get an idle session, or create a new one if none is available.
+    ///
+    /// The session goes back to the pool when the returned guard is dropped.
+    /// It is cleared first, so that observers, retained nodes and modes set
+    /// by one user never leak to the next.
+    pub fn get(&self) -> PooledSession<'_> {
+        let session = self.idle
+            .lock()
+            .ok()
+            .and_then(|mut idle| idle.pop())
+            .unwrap_or_else(|| Session::new(self.model.clone()));
+        PooledSession {
+            pool: self,
+            session: Some(session),
+        }
+    }
+
+    /// Number of idle sessions in the pool.
+    pub fn idle(&self) -> usize {
+        self.idle.lock().map(|idle| idle.len()).unwrap_or(0)
+    }
+}
+
+/// A session borrowed from a SessionPool.
+pub struct PooledSession<'a> {
+    pool: &'a SessionPool,
+    session: Option<Session>,
+}
+
+impl<'a> Deref for PooledSession<'a> {
+    type Target = Session;
+    fn deref(&self) -> &Session {
+        self.session.as_ref().unwrap()
+    }
+}
+
+impl<'a> DerefMut for PooledSession<'a> {
+    fn deref_mut(&mut self) -> &mut Session {
+        self.session.as_mut().unwrap()
+    }
+}
+
+impl<'a> Drop for PooledSession<'a> {
+    fn drop(&mut self) {
+        if let Some(mut session) = self.session.take() {
+            if session.clear().is_err() {
+                return;
+            }
+            if let Ok(mut idle) = self.pool.idle.lock() {
+                if idle.len() < self.pool.capacity {
+                    idle.push(session)
+                }
+            }
+        }
+    }
+}
+
+#[allow(dead_code)]
+fn assert_send_sync() {
+    fn is_send_sync<T: Send + Sync>() {}
+    is_send_sync::<Model>();
+    is_send_sync::<Session>();
+    is_send_sync::<SessionPool>();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use Matrix;
+
+    #[test]
+    fn session_in_threads() {
+        let model = Arc::new(::for_path("tests/plus3.pb").unwrap());
+        let input = model.node_id_by_name("input").unwrap();
+        let output = model.node_id_by_name("output").unwrap();
+        let pool = Arc::new(SessionPool::new(model, 2));
+        let workers: Vec<_> = (0..4)
+            .map(|i| {
+                let pool = pool.clone();
+                thread::spawn(move || {
+                    let mut session = pool.get();
+                    let value = Matrix::f32s(&[1], &[i as f32]).unwrap();
+                    session.run(vec![(input, value)], output).unwrap()
+                })
+            })
+            .collect();
+        for (i, worker) in workers.into_iter().enumerate() {
+            let result = worker.join().unwrap();
+            assert_eq!(result[0], Matrix::f32s(&[1], &[i as f32 + 3.0]).unwrap());
+        }
+        assert!(pool.idle() >= 1 && pool.idle() <= 2);
+    }
+
+    #[test]
+    fn pooled_sessions_are_cleared() {
+        struct Spy;
+        impl ::Observer for Spy {}
+        let model = Arc::new(::for_path("tests/plus3.pb").unwrap());
+        let pool = SessionPool::new(model, 1);
+        let value = Matrix::f32s(&[1], &[1.0]).unwrap();
+        let outputs = {
+            let mut session = pool.get();
+            session.add_observer(Box::new(Spy));
+            session.retain(1);
+            session.set_incremental(true);
+            session.set_profiling(true);
+            session.run(vec![(0, value.clone())], 2).unwrap();
+            assert!(session.outputs[1].is_some());
+            session.outputs.as_ptr()
+        };
+        assert_eq!(pool.idle(), 1);
+        let mut session = pool.get();
+        assert_eq!(session.outputs.as_ptr(), outputs);
+        assert!(session.clear_observers().is_empty());
+        assert!(!session.is_incremental());
+        assert!(session.profile().is_none());
+        assert!(session.outputs.iter().all(|o| o.is_none()));
+        session.run(vec![(0, value)], 2).unwrap();
+        assert!(session.outputs[1].is_none());
+    }
+
+    #[test]
+    fn session_is_movable() {
+        let model = Arc::new(::for_path("tests/plus3.pb").unwrap());
+        let mut session = Session::new(model.clone());
+        let result = thread::spawn(move || {
+            let value = Matrix::f32s(&[1], &[1.0]).unwrap();
+            session.run(vec![(0, value)], 2).unwrap()
+        }).join()
+            .unwrap();
+        assert_eq!(result[0], Matrix::f32s(&[1], &[4.0]).unwrap());
+    }
+}
@@ -86,9 +86,19 @@ impl NodeDef {
 }
 
 impl node_def::NodeDef {
+    /// Error for a missing or invalid attribute of this node.
+    pub fn malformed_attr(&self, name: &str, reason: &str) -> ::errors::ErrorKind {
+        ::errors::ErrorKind::MalformedAttribute(
+            self.get_name().to_string(),
+            self.get_op().to_string(),
+            name.to_string(),
+            reason.to_string(),
+        )
+    }
+
     pub fn get_attr_raw_str(&self, name: &str) -> ::Result<&[u8]> {
         Ok(self.get_attr_opt_raw_str(name)?
-            .ok_or_else(|| format!("Node {} ({}) expected string attr {}", self.get_name(), self.get_op(), name))?)
+            .ok_or_else(|| self.malformed_attr(name, "expected string"))?)
     }
 
     pub fn get_attr_opt_raw_str(&self, name: &str) -> ::Result<Option<&[u8]>> {
@@ -97,21 +107,30 @@ impl node_def::NodeDef {
 
     pub fn get_attr_str(&self, name: &str) -> ::Result<String> {
         Ok(self.get_attr_opt_str(name)?
-            .ok_or_else(|| format!("Node {} ({}) expected UTF-8 string attr {}", self.get_name(), self.get_op(), name))?)
+            .ok_or_else(|| self.malformed_attr(name, "expected UTF-8 string"))?)
     }
 
     pub fn get_attr_opt_str(&self, name: &str) -> ::Result<Option<String>> {
         if let Some(s) = self.get_attr_opt_raw_str(name)? {
             Ok(Some(String::from_utf8(s.to_vec())
-                .map_err(|_| format!("Node {} ({}) expected an UTF-8 string for attr {}", self.get_name(), self.get_op(), name))?))
+                .map_err(|_| self.malformed_attr(name, "expected UTF-8 string"))?))
         } else {
             Ok(None)
         }
     }
 
+    pub fn get_attr_bool(&self, name: &str) -> ::Result<bool> {
+        Ok(self.get_attr_opt_bool(name)?
+            .ok_or_else(|| self.malformed_attr(name, "expected bool"))?)
+    }
+
+    pub fn get_attr_opt_bool(&self, name: &str) -> ::Result<Option<bool>> {
+        Ok(self.get_attr().get(name).map(|v| v.get_b()))
+    }
+
     pub fn get_attr_datatype(&self, name: &str) -> ::Result<types::DataType> {
         Ok(self.get_attr_opt_datatype(name)?
-            .ok_or_else(|| format!("Node {} ({}) expected datatype attr {}", self.get_name(), self.get_op(), name))?)
+            .ok_or_else(|| self.malformed_attr(name, "expected datatype"))?)
     }
 
     pub fn get_attr_opt_datatype(&self, name: &str) -> ::Result<Option<types::DataType>> {
@@ -122,14 +141,25 @@ impl node_def::NodeDef {
         }
     }
 
+    pub fn get_attr_float(&self, name: &str) -> ::Result<f32> {
+        Ok(self.get_attr_opt_float(name)?
+            .ok_or_else(|| self.malformed_attr(name, "expected float"))?)
+    }
+
+    pub fn get_attr_opt_float(&self, name: &str) -> ::Result<Option<f32>> {
+        Ok(self.get_attr().get(name).map(|v| v.get_f()))
+    }
+
     pub fn get_attr_tensor(&self, name: &str) -> ::Result<::matrix::Matrix> {
         Ok(self.get_attr_opt_tensor(name)?
-            .ok_or_else(|| format!("Node {} ({}) expected tensor attr {}", self.get_name(), self.get_op(), name))?)
+            .ok_or_else(|| self.malformed_attr(name, "expected tensor"))?)
     }
 
     pub fn get_attr_opt_tensor(&self, name: &str) -> ::Result<Option<::matrix::Matrix>> {
         if let Some(t) = self.get_attr().get(name).map(|v| v.get_tensor()) {
-            Ok(Some(::matrix::Matrix::from_pb(&t)?))
+            use errors::ResultExt;
+            Ok(Some(::matrix::Matrix::from_pb(&t)
+                .chain_err(|| self.malformed_attr(name, "invalid tensor"))?))
         } else {
             Ok(None)
         }
@@ -137,12 +167,12 @@ impl node_def::NodeDef {
 
     pub fn get_attr_int<T: ::num_traits::FromPrimitive>(&self, name: &str) -> ::Result<T> {
         Ok(self.get_attr_opt_int(name)?
-            .ok_or_else(|| format!("Node {} ({}) expected int attr {}", self.get_name(), self.get_op(), name))?)
+            .ok_or_else(|| self.malformed_attr(name, "expected int"))?)
     }
     pub fn get_attr_opt_int<T: ::num_traits::FromPrimitive>(&self, name: &str) -> ::Result<Option<T>> {
         if let Some(i) = self.get_attr().get(name) {
             Ok(Some(T::from_i64(i.get_i())
-                .ok_or_else(|| format!("Node {} ({}) expected int attr {}", self.get_name(), self.get_op(), name))?))
+                .ok_or_else(|| self.malformed_attr(name, "int out of range"))?))
         } else {
             Ok(None)
         }
@@ -150,13 +180,13 @@ impl node_def::NodeDef {
 
     pub fn get_attr_list_int<T: ::num_traits::FromPrimitive>(&self, name: &str) -> ::Result<Vec<T>> {
         Ok(self.get_attr_opt_list_int(name)?
-            .ok_or_else(|| format!("Node {} ({}) expected int attr {}", self.get_name(), self.get_op(), name))?)
+            .ok_or_else(|| self.malformed_attr(name, "expected int"))?)
     }
 
     pub fn get_attr_opt_list_int<T: ::num_traits::FromPrimitive>(&self, name: &str) -> ::Result<Option<Vec<T>>> {
         if let Some(list) = self.get_attr().get(name) {
             Ok(Some(list.get_list().get_i().iter().map(|i| T::from_i64(*i)
-                .ok_or_else(|| format!("Node {} ({}) expected list<int> attr {}", self.get_name(), self.get_op(), name).into()))
+                .ok_or_else(|| self.malformed_attr(name, "int out of range").into()))
                 .collect::<::Result<Vec<T>>>()?))
         } else {
             Ok(None)
@@ -180,6 +210,14 @@ impl<'a> From<&'a str> for AttrValue {
     }
 }
 
+impl From<bool> for AttrValue {
+    fn from(t: bool) -> AttrValue {
+        let mut value = attr_value::AttrValue::new();
+        value.set_b(t);
+        value
+    }
+}
+
 impl From<i64> for AttrValue {
     fn from(t: i64) -> AttrValue {
         let mut value = attr_value::AttrValue::new();
@@ -0,0 +1,163 @@
+//! Load-time report of the parts of a model tfdeploy can not run.
+//!
+//! Unsupported ops do not prevent a model from loading, as they may never
+//! be evaluated. They are recorded while the model is built, so it can be
+//! vetted before running it, or rejected at load with `Model::new_checked`.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use errors::{Error, ErrorKind};
+use tfpb::types::DataType;
+
+/// Unsupported ops and datatypes found when loading a model.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Validation {
+    /// Unknown op names, with the names of the nodes using them.
+    pub unsupported_ops: BTreeMap<String, Vec<String>>,
+    /// Nodes whose op is not implemented for the datatype they use, with
+    /// this op and datatype.
+    pub unsupported_datatypes: Vec<(String, String, DataType)>,
+}
+
+impl Validation {
+    /// Is everything in the model supported ?
+    pub fn is_ok(&self) -> bool {
+        self.unsupported_ops.is_empty() && self.unsupported_datatypes.is_empty()
+    }
+
+    /// Record the failure to build the op of `node`. Returns false if the
+    /// error is not about an unsupported op or datatype.
+    pub(crate) fn record(&mut self, node: &str, error: &Error) -> bool {
+        match *error.kind() {
+            ErrorKind::UnsupportedOp(_, ref op) => self.unsupported_ops
+                .entry(op.clone())
+                .or_insert_with(Vec::new)
+                .push(node.to_string()),
+            ErrorKind::UnsupportedDatatype(_, ref op, dt) => self.unsupported_datatypes
+                .push((node.to_string(), op.clone(), dt)),
+            _ => return false,
+        }
+        true
+    }
+
+    /// The first unsupported op, or else datatype, as an error of the
+    /// matching kind, caused by the whole report. `None` if everything is
+    /// supported.
+    pub fn error(&self) -> Option<Error> {
+        let kind = if let Some((op, nodes)) = self.unsupported_ops.iter().next() {
+            ErrorKind::UnsupportedOp(nodes[0].clone(), op.clone())
+        } else if let Some(&(ref node, ref op, dt)) = self.unsupported_datatypes.first() {
+            ErrorKind::UnsupportedDatatype(node.clone(), op.clone(), dt)
+        } else {
+            return None;
+        };
+        let report = Error::from(format!("Model is not supported:\n{}", self));
+        Some(Error::with_chain(report, kind))
+    }
+}
+
+impl fmt::Display for Validation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (op, nodes) in &self.unsupported_ops {
+            writeln!(f, "unsupported op {}: {}", op, nodes.join(", "))?;
+        }
+        for &(ref node, ref op, dt) in &self.unsupported_datatypes {
+            let kind = ErrorKind::UnsupportedDatatype(node.clone(), op.clone(), dt);
+            writeln!(f, "{}", kind)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {tfpb, ErrorKind, Matrix, Model};
+    use tfpb::types::DataType::*;
+
+    fn graph() -> tfpb::graph::GraphDef {
+        tfpb::graph()
+            .node(tfpb::node().name("a").op("Placeholder"))
+            .node(tfpb::node().name("foo1").op("Foo").input("a"))
+            .node(tfpb::node().name("foo2").op("Foo").input("foo1"))
+            .node(tfpb::node().name("bar").op("Bar").input("a"))
+            .node(
+                tfpb::node()
+                    .name("add")
+                    .op("Add")
+                    .attr("T", DT_COMPLEX64)
+                    .input("a")
+                    .input("a"),
+            )
+    }
+
+    #[test]
+    fn report() {
+        let model = Model::new(graph()).unwrap();
+        let validation = model.validation();
+        assert!(!validation.is_ok());
+        assert_eq!(
+            validation.unsupported_ops["Foo"],
+            vec!["foo1".to_string(), "foo2".to_string()]
+        );
+        assert_eq!(validation.unsupported_ops["Bar"], vec!["bar".to_string()]);
+        assert_eq!(validation.unsupported_datatypes.len(), 1);
+        assert_eq!(validation.unsupported_datatypes[0].0, "add");
+        assert!(
+            validation
+                .to_string()
+                .contains("Node add: Add is not implemented for DT_COMPLEX64")
+        );
+
+        let a = Matrix::i32s(&[1], &[1]).unwrap();
+        let err = model.run_with_names(vec![("a", a.clone())], "add").unwrap_err();
+        match *err.kind() {
+            ErrorKind::UnsupportedDatatype(ref node, ref op, DT_COMPLEX64) => {
+                assert_eq!((&**node, &**op), ("add", "Add"))
+            }
+            ref e => panic!("unexpected error {:?}", e),
+        }
+
+        let err = model.run_with_names(vec![("a", a)], "foo1").unwrap_err();
+        match *err.kind() {
+            ErrorKind::UnsupportedOp(ref node, ref op) => {
+                assert_eq!((&**node, &**op), ("foo1", "Foo"))
+            }
+            ref e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn checked() {
+        let err = Model::new_checked(graph()).err().unwrap();
+        match *err.kind() {
+            ErrorKind::UnsupportedOp(ref node, ref op) => {
+                assert_eq!((&**node, &**op), ("bar", "Bar"))
+            }
+            ref e => panic!("unexpected error {:?}", e),
+        }
+        assert!(err.iter().any(|e| e.to_string().contains("unsupported op Foo: foo1, foo2")));
+
+        let complex = tfpb::graph()
+            .node(tfpb::node().name("a").op("Placeholder"))
+            .node(
+                tfpb::node()
+                    .name("add")
+                    .op("Add")
+                    .attr("T", DT_COMPLEX64)
+                    .input("a")
+                    .input("a"),
+            );
+        let err = Model::new_checked(complex).err().unwrap();
+        match *err.kind() {
+            ErrorKind::UnsupportedDatatype(ref node, ref op, DT_COMPLEX64) => {
+                assert_eq!((&**node, &**op), ("add", "Add"))
+            }
+            ref e => panic!("unexpected error {:?}", e),
+        }
+
+        let plus3 = Model::graphdef_for_path("tests/plus3.pb").unwrap();
+        let model = Model::new_checked(plus3).unwrap();
+        assert!(model.validation().is_ok());
+    }
+}